@@ -0,0 +1,157 @@
+use std::ops::Range;
+
+use crate::{
+	audio::{
+		self,
+		Block,
+		Format,
+		Source,
+	},
+	plan::Job,
+	Result,
+};
+
+/// Samples per CD sector.
+const SECTOR: u64 = 588;
+/// AccurateRip ignores this many samples at the start of the first track and the end of the last one, since drives can't read them reliably.
+const SKIPPED: u64 = 5 * SECTOR;
+
+/// Checksums of one track, computed from the decoded audio.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Checksums {
+	/// The AccurateRip v1 checksum
+	pub v1: u32,
+	/// The AccurateRip v2 checksum
+	pub v2: u32,
+	/// The CRC32 of the raw audio, as shown by EAC as "Copy CRC"
+	pub crc32: u32,
+}
+
+impl Checksums {
+	/// Tags holding the checksums as 8 uppercase hexadecimal digits.
+	pub fn tags(&self) -> [(String, String); 3] {
+		[
+			("ACCURATERIPV1".into(), format!("{:08X}", self.v1)),
+			("ACCURATERIPV2".into(), format!("{:08X}", self.v2)),
+			("CRC32".into(), format!("{:08X}", self.crc32)),
+		]
+	}
+}
+
+// Computes the checksums of one track incrementally.
+struct Track {
+	/// 1-based position of the next sample in the track
+	n: u64,
+	/// Samples before this position are ignored by AccurateRip
+	check_start: u64,
+	/// Samples after this position are ignored by AccurateRip
+	check_end: u64,
+	v1: u32,
+	v2: u32,
+	crc32: crc32fast::Hasher,
+}
+
+impl Track {
+	fn new(len: u64, first: bool, last: bool) -> Self {
+		Self {
+			n: 1,
+			check_start: if first { SKIPPED } else { 0 },
+			check_end: if last {
+				len.saturating_sub(SKIPPED)
+			} else {
+				len
+			},
+			v1: 0,
+			v2: 0,
+			crc32: crc32fast::Hasher::new(),
+		}
+	}
+
+	fn update(&mut self, block: &Block, range: Range<usize>, buf: &mut Vec<u8>) {
+		buf.clear();
+		block.write_le(range.clone(), 2, buf);
+		self.crc32.update(buf);
+
+		let (left, right) = (block.channel(0), block.channel(1));
+		for i in range {
+			if self.n >= self.check_start && self.n <= self.check_end {
+				// A stereo sample as one little-endian 32-bit word
+				let sample = u32::from(left[i] as u16) | u32::from(right[i] as u16) << 16;
+				let product = u64::from(sample) * (self.n & 0xffff_ffff);
+				self.v1 = self.v1.wrapping_add(product as u32);
+				self.v2 = self
+					.v2
+					.wrapping_add(product as u32)
+					.wrapping_add((product >> 32) as u32);
+			}
+			self.n += 1;
+		}
+	}
+
+	fn finish(self) -> Checksums {
+		Checksums {
+			v1: self.v1,
+			v2: self.v2,
+			crc32: self.crc32.finalize(),
+		}
+	}
+}
+
+// Opens the source of a job if it's CD audio, which is all AccurateRip knows about.
+pub(crate) fn open(job: &Job) -> Result<Option<Source>> {
+	if !audio::is_supported(&job.source, &job.kind) {
+		return Ok(None);
+	}
+
+	let src = Source::open(&job.source, &job.kind)?;
+	Ok((src.format() == Format::CD).then_some(src))
+}
+
+/// Returns whether checksums can be computed for the tracks of a job.
+pub fn supports(job: &Job) -> bool {
+	matches!(open(job), Ok(Some(_)))
+}
+
+/// Computes the checksums of every track of a job by decoding its source.
+///
+/// Returns `None` if the source can't be decoded or isn't CD audio (44.1 kHz, 16 bits, stereo).
+pub fn compute(job: &Job) -> Result<Option<Vec<Checksums>>> {
+	let Some(mut src) = open(job)? else {
+		return Ok(None);
+	};
+
+	let ranges = audio::sample_ranges(&job.cuts, Format::CD, src.samples().unwrap_or(u64::MAX));
+	let end = ranges.iter().map(|r| r.end).max().unwrap_or(0);
+	let mut tracks = job
+		.cuts
+		.iter()
+		.zip(&ranges)
+		.map(|(cut, r)| Track::new(r.end - r.start, cut.first, cut.last))
+		.collect::<Vec<_>>();
+
+	let mut block = Block::default();
+	let mut buf = Vec::new();
+	if let Some(first) = ranges.first() {
+		src.seek(first.start)?;
+	}
+	while src.read(&mut block)? {
+		let block_end = block.start + block.len as u64;
+		for (range, t) in ranges.iter().zip(&mut tracks) {
+			let start = range.start.max(block.start);
+			let end = range.end.min(block_end);
+			if start < end {
+				t.update(
+					&block,
+					(start - block.start) as usize..(end - block.start) as usize,
+					&mut buf,
+				);
+			}
+		}
+
+		if block_end >= end {
+			break;
+		}
+	}
+
+	Ok(Some(tracks.into_iter().map(Track::finish).collect()))
+}
@@ -0,0 +1,346 @@
+pub mod flac;
+pub mod wav;
+
+use std::{
+	fs::File,
+	io::{
+		self,
+		Read,
+		Seek,
+		SeekFrom,
+	},
+	ops::Range,
+	path::{
+		Path,
+		PathBuf,
+	},
+};
+
+use crate::{
+	cue::FRAMES_PER_SEC,
+	plan::Cut,
+	Error,
+	Result,
+};
+
+/// Layout of interleaved PCM samples.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Format {
+	pub channels: u16,
+	pub sample_rate: u32,
+	pub bits_per_sample: u16,
+}
+
+impl Format {
+	/// CD audio: 44.1 kHz, 16 bits, stereo.
+	pub const CD: Self = Self {
+		channels: 2,
+		sample_rate: 44100,
+		bits_per_sample: 16,
+	};
+
+	pub const fn bytes_per_sample(self) -> u16 {
+		self.bits_per_sample.div_ceil(8)
+	}
+
+	/// Size of one sample for every channel, in bytes.
+	pub const fn block_align(self) -> u16 {
+		self.channels * self.bytes_per_sample()
+	}
+
+	/// Converts a position in CD frames to a sample offset.
+	pub const fn frames_to_samples(self, frames: u64) -> u64 {
+		frames * self.sample_rate as u64 / FRAMES_PER_SEC
+	}
+}
+
+/// Uncompressed audio inside a file: WAV, AIFF or raw CD audio.
+#[derive(Debug, Clone)]
+pub struct Pcm {
+	pub format: Format,
+	/// Body of the `fmt ` chunk used when writing this audio to a WAV file
+	pub fmt_chunk: Vec<u8>,
+	/// Whether samples are stored big-endian (AIFF and `MOTOROLA` raw files)
+	pub big_endian: bool,
+	/// Whether 8-bit samples are signed (AIFF) rather than unsigned (WAV)
+	pub signed_8bit: bool,
+	/// Offset of the first sample in the file
+	pub data_start: u64,
+	/// Size of the sample data in bytes
+	pub data_len: u64,
+}
+
+pub(crate) fn malformed(path: &Path, msg: impl Into<String>) -> Error {
+	Error::Format {
+		path: path.to_path_buf(),
+		msg: msg.into(),
+	}
+}
+
+/// Extensions of files [`Source::open`] can read.
+pub const EXTS: &[&str] = &["wav", "aif", "aiff", "aifc", "bin", "raw", "pcm", "flac"];
+
+/// Returns whether `path` looks like a file [`Source::open`] can read; `kind` is the file type from the cuesheet.
+pub fn is_supported(path: &Path, kind: &str) -> bool {
+	kind.eq_ignore_ascii_case("BINARY")
+		|| kind.eq_ignore_ascii_case("MOTOROLA")
+		|| path
+			.extension()
+			.and_then(|s| s.to_str())
+			.is_some_and(|ext| EXTS.iter().any(|s| s.eq_ignore_ascii_case(ext)))
+}
+
+impl Pcm {
+	/// Opens a file containing uncompressed audio; `kind` is the file type from the cuesheet.
+	///
+	/// Files that are neither WAV nor AIFF are read as raw CD audio, big-endian if `kind` is `MOTOROLA`.
+	pub fn open(path: &Path, kind: &str) -> Result<(File, Self)> {
+		let mut file = File::open(path).map_err(|e| Error::io(path, e))?;
+		let mut magic = [0; 12];
+		let n = read_full(&mut file, &mut magic).map_err(|e| Error::io(path, e))?;
+
+		let pcm = match &magic[..n] {
+			[b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E'] => {
+				wav::read_riff(&mut file, path)?
+			}
+			[b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', c] if matches!(c, b'F' | b'C') => {
+				wav::read_aiff(&mut file, path, *c == b'C')?
+			}
+			_ => {
+				let len = file.metadata().map_err(|e| Error::io(path, e))?.len();
+				Self {
+					format: Format::CD,
+					fmt_chunk: wav::pcm_fmt_chunk(Format::CD),
+					big_endian: kind.eq_ignore_ascii_case("MOTOROLA"),
+					signed_8bit: false,
+					data_start: 0,
+					data_len: len,
+				}
+			}
+		};
+
+		file.seek(SeekFrom::Start(pcm.data_start))
+			.map_err(|e| Error::io(path, e))?;
+		Ok((file, pcm))
+	}
+
+	/// Number of samples per channel.
+	pub fn samples(&self) -> u64 {
+		self.data_len / u64::from(self.format.block_align())
+	}
+
+	/// Converts samples read from the file into the byte order WAV files use, in place.
+	pub fn to_wav_order(&self, buf: &mut [u8]) {
+		let width = usize::from(self.format.bytes_per_sample());
+		if self.big_endian && width > 1 {
+			for s in buf.chunks_exact_mut(width) {
+				s.reverse();
+			}
+		}
+		if self.signed_8bit && width == 1 {
+			for b in buf {
+				*b ^= 0x80;
+			}
+		}
+	}
+}
+
+/// Decoded samples, stored one channel after another.
+#[derive(Debug, Default)]
+pub struct Block {
+	/// Position of the first sample in the source
+	pub start: u64,
+	/// Number of samples per channel
+	pub len: usize,
+	pub channels: usize,
+	buf: Vec<i32>,
+}
+
+impl Block {
+	pub fn channel(&self, ch: usize) -> &[i32] {
+		&self.buf[ch * self.len..(ch + 1) * self.len]
+	}
+
+	/// Appends the samples in `range` to `buf`, interleaved, as little-endian integers `width` bytes wide.
+	pub fn write_le(&self, range: Range<usize>, width: usize, buf: &mut Vec<u8>) {
+		for i in range {
+			for ch in 0..self.channels {
+				buf.extend_from_slice(&self.channel(ch)[i].to_le_bytes()[..width]);
+			}
+		}
+	}
+}
+
+/// Converts the positions of cuts to sample ranges of a source with `total` samples.
+pub fn sample_ranges(cuts: &[Cut], format: Format, total: u64) -> Vec<Range<u64>> {
+	cuts.iter()
+		.map(|cut| {
+			let start = format.frames_to_samples(cut.start).min(total);
+			let end = cut
+				.end
+				.map_or(total, |e| format.frames_to_samples(e).min(total));
+			start..end.max(start)
+		})
+		.collect()
+}
+
+// Samples per channel read from uncompressed files at once.
+const PCM_BLOCK: usize = 4096;
+
+enum Reader {
+	Pcm { file: File, pcm: Pcm, pos: u64, bytes: Vec<u8> },
+	Flac(Box<flac::Reader>),
+}
+
+/// A file audio can be decoded from: WAV, AIFF, raw CD audio or FLAC.
+pub struct Source {
+	path: PathBuf,
+	reader: Reader,
+}
+
+impl Source {
+	/// Opens an audio file, detecting the format from its contents; `kind` is the file type from the cuesheet.
+	pub fn open(path: &Path, kind: &str) -> Result<Self> {
+		let mut magic = [0; 4];
+		let n = File::open(path)
+			.and_then(|mut f| read_full(&mut f, &mut magic))
+			.map_err(|e| Error::io(path, e))?;
+
+		let reader = if &magic[..n] == b"fLaC" {
+			Reader::Flac(Box::new(flac::Reader::open(path)?))
+		} else {
+			let (file, pcm) = Pcm::open(path, kind)?;
+			Reader::Pcm {
+				file,
+				pcm,
+				pos: 0,
+				bytes: Vec::new(),
+			}
+		};
+
+		Ok(Self {
+			path: path.to_path_buf(),
+			reader,
+		})
+	}
+
+	pub fn format(&self) -> Format {
+		match &self.reader {
+			Reader::Pcm { pcm, .. } => pcm.format,
+			Reader::Flac(r) => r.format(),
+		}
+	}
+
+	/// Number of samples per channel, if known.
+	pub fn samples(&self) -> Option<u64> {
+		match &self.reader {
+			Reader::Pcm { pcm, .. } => Some(pcm.samples()),
+			Reader::Flac(r) => r.samples(),
+		}
+	}
+
+	/// Body of the `fmt ` chunk to use when writing this audio to a WAV file.
+	pub fn fmt_chunk(&self) -> Vec<u8> {
+		match &self.reader {
+			Reader::Pcm { pcm, .. } => pcm.fmt_chunk.clone(),
+			Reader::Flac(r) => wav::pcm_fmt_chunk(r.format()),
+		}
+	}
+
+	/// Bodies of the FLAC `PICTURE` metadata blocks in the source.
+	pub fn pictures(&self) -> &[Vec<u8>] {
+		match &self.reader {
+			Reader::Pcm { .. } => &[],
+			Reader::Flac(r) => r.pictures(),
+		}
+	}
+
+	/// Skips to sample `pos`; FLAC sources continue at the start of the frame that contains it.
+	///
+	/// Blocks read afterwards can start before `pos`, so callers go by [`Block::start`].
+	pub fn seek(&mut self, pos: u64) -> Result<()> {
+		match &mut self.reader {
+			Reader::Flac(r) => r.seek(pos).map_err(|e| Error::io(&self.path, e)),
+			Reader::Pcm { file, pcm, pos: p, .. } => {
+				*p = pos.min(pcm.samples());
+				let offset = pcm.data_start + *p * u64::from(pcm.format.block_align());
+				file.seek(SeekFrom::Start(offset))
+					.map(drop)
+					.map_err(|e| Error::io(&self.path, e))
+			}
+		}
+	}
+
+	/// Decodes the next samples into `block`; returns `false` at the end of the stream.
+	pub fn read(&mut self, block: &mut Block) -> Result<bool> {
+		match &mut self.reader {
+			Reader::Flac(r) => r.read(block).map_err(|e| flac::decode_error(&self.path, e)),
+			Reader::Pcm {
+				file,
+				pcm,
+				pos,
+				bytes,
+			} => {
+				let format = pcm.format;
+				let align = usize::from(format.block_align());
+				let len = (pcm.samples() - *pos).min(PCM_BLOCK as u64) as usize;
+				if len == 0 {
+					return Ok(false);
+				}
+
+				bytes.resize(len * align, 0);
+				file.read_exact(bytes)
+					.map_err(|e| Error::io(&self.path, e))?;
+				pcm.to_wav_order(bytes);
+
+				let channels = usize::from(format.channels);
+				block.start = *pos;
+				block.len = len;
+				block.channels = channels;
+				block.buf.resize(len * channels, 0);
+				for (i, frame) in bytes.chunks_exact(align).enumerate() {
+					for (ch, s) in frame
+						.chunks_exact(usize::from(format.bytes_per_sample()))
+						.enumerate()
+					{
+						block.buf[ch * len + i] = wav::decode_sample(s, format.bits_per_sample);
+					}
+				}
+
+				*pos += len as u64;
+				Ok(true)
+			}
+		}
+	}
+}
+
+/// Keeps the last value of every tag, compared case-insensitively, in the order they first appear.
+///
+/// This mirrors how repeated `-metadata` options behave in ffmpeg.
+pub fn dedup_tags(tags: &[(String, String)]) -> Vec<(&str, &str)> {
+	let mut out = Vec::<(&str, &str)>::with_capacity(tags.len());
+	for (k, v) in tags {
+		match out.iter_mut().find(|(k2, _)| k2.eq_ignore_ascii_case(k)) {
+			Some(x) => x.1 = v,
+			None => out.push((k, v)),
+		}
+	}
+
+	out.retain(|(_, v)| !v.is_empty());
+	out
+}
+
+// Like Read::read_exact but returns how many bytes were read if the reader hits EOF.
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+	let mut n = 0;
+	while n < buf.len() {
+		match r.read(&mut buf[n..]) {
+			Ok(0) => break,
+			Ok(read) => n += read,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+			Err(e) => return Err(e),
+		}
+	}
+
+	Ok(n)
+}
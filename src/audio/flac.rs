@@ -0,0 +1,983 @@
+//! FLAC decoding (with claxon) and a small encoder using fixed predictors and Rice coding.
+
+use std::{
+	fs::File,
+	io::{
+		self,
+		Read,
+		Seek,
+		SeekFrom,
+		Write,
+	},
+	ops::Range,
+	path::Path,
+};
+
+use claxon::{
+	frame::FrameReader,
+	input::BufferedReader,
+	metadata::StreamInfo,
+};
+use md5::{
+	Digest,
+	Md5,
+};
+
+use super::{
+	dedup_tags,
+	malformed,
+	Block,
+	Format,
+};
+use crate::{
+	Error,
+	Result,
+};
+
+const STREAMINFO: u8 = 0;
+const PADDING: u8 = 1;
+const VORBIS_COMMENT: u8 = 4;
+const PICTURE: u8 = 6;
+
+/// Decodes a FLAC file.
+pub struct Reader {
+	/// Shares its position with the file `frames` reads from, which is replaced after every seek
+	file: File,
+	frames: FrameReader<BufferedReader<File>>,
+	info: StreamInfo,
+	pictures: Vec<Vec<u8>>,
+	/// Offset of the first frame
+	audio_start: u64,
+	len: u64,
+	/// Position of the next sample
+	pos: u64,
+}
+
+pub(super) fn decode_error(path: &Path, e: claxon::Error) -> Error {
+	match e {
+		claxon::Error::IoError(e) => Error::io(path, e),
+		e => malformed(path, e.to_string()),
+	}
+}
+
+// Reads the bodies of PICTURE blocks, which claxon skips, and returns them with the offset of the first frame.
+fn read_pictures(f: &mut File) -> io::Result<(Vec<Vec<u8>>, u64)> {
+	let mut pictures = Vec::new();
+	f.seek(SeekFrom::Start(4))?;
+	loop {
+		let mut header = [0; 4];
+		f.read_exact(&mut header)?;
+		let len = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+		if header[0] & 0x7f == PICTURE {
+			let mut body = vec![0; len as usize];
+			f.read_exact(&mut body)?;
+			pictures.push(body);
+		} else {
+			f.seek(SeekFrom::Current(i64::from(len)))?;
+		}
+
+		if header[0] & 0x80 != 0 {
+			return Ok((pictures, f.stream_position()?));
+		}
+	}
+}
+
+// Parses the frame header at the start of `b` and returns the number of its first sample, or `None` if it isn't one.
+fn frame_header(b: &[u8], block_size: u64) -> Option<u64> {
+	if b.len() < 5 || b[0] != 0xff || b[1] & 0xfe != 0xf8 {
+		return None;
+	}
+	let (size, rate) = (b[2] >> 4, b[2] & 0x0f);
+	let (assignment, bps) = (b[3] >> 4, (b[3] >> 1) & 7);
+	if size == 0 || rate == 15 || assignment > 10 || bps == 3 || b[3] & 1 != 0 {
+		return None;
+	}
+
+	// Fixed block size streams number frames, others number samples, in UTF-8's coding
+	let extra = match b[4].leading_ones() {
+		0 => 0,
+		n @ 2..=7 => n as usize - 1,
+		_ => return None,
+	};
+	let mut n = u64::from(b[4] & (0x7f >> extra));
+	for &c in b.get(5..5 + extra)? {
+		if c & 0xc0 != 0x80 {
+			return None;
+		}
+		n = n << 6 | u64::from(c & 0x3f);
+	}
+
+	// Block sizes and sample rates that don't fit their 4 bit codes follow the number
+	let size_len = match size {
+		6 => 1,
+		7 => 2,
+		_ => 0,
+	};
+	let rate_len = match rate {
+		12 => 1,
+		13 | 14 => 2,
+		_ => 0,
+	};
+	let end = 5 + extra + size_len + rate_len;
+	(*b.get(end)? == crc8(&b[..end])).then_some(if b[1] & 1 == 0 { n * block_size } else { n })
+}
+
+impl Reader {
+	pub fn open(path: &Path) -> Result<Self> {
+		let io_err = |e| Error::io(path, e);
+		let mut f = File::open(path).map_err(io_err)?;
+		let len = f.metadata().map_err(io_err)?.len();
+		let (pictures, audio_start) = read_pictures(&mut f).map_err(io_err)?;
+		f.seek(SeekFrom::Start(0)).map_err(io_err)?;
+
+		let inner = claxon::FlacReader::new(f).map_err(|e| decode_error(path, e))?;
+		let info = inner.streaminfo();
+		if info.bits_per_sample > 32 || info.channels > 8 {
+			return Err(malformed(path, "the FLAC stream info is invalid"));
+		}
+
+		let mut file = inner.into_inner();
+		file.seek(SeekFrom::Start(audio_start)).map_err(io_err)?;
+		Ok(Self {
+			frames: FrameReader::new(BufferedReader::new(file.try_clone().map_err(io_err)?)),
+			file,
+			info,
+			pictures,
+			audio_start,
+			len,
+			pos: 0,
+		})
+	}
+
+	// Continues decoding at the frame at byte `offset`, which starts with sample `pos`.
+	fn reset(&mut self, offset: u64, pos: u64) -> io::Result<()> {
+		let mut f = self.file.try_clone()?;
+		f.seek(SeekFrom::Start(offset))?;
+		self.frames = FrameReader::new(BufferedReader::new(f));
+		self.pos = pos;
+		Ok(())
+	}
+
+	// Finds the first frame starting from byte `from` to before `to`, and returns its offset and first sample.
+	// A candidate is only accepted if claxon decodes it, which checks the CRC of the whole frame.
+	fn find_frame(&self, from: u64, to: u64) -> io::Result<Option<(u64, u64)>> {
+		// The longest frame header
+		const HEADER: usize = 16;
+		let block_size = u64::from(self.info.max_block_size);
+		let mut f = self.file.try_clone()?;
+		let mut buf = vec![0; 1 << 16];
+		let mut start = from;
+		while start < to {
+			f.seek(SeekFrom::Start(start))?;
+			let n = super::read_full(&mut f, &mut buf)?;
+			let eof = n < buf.len();
+			let scan = if eof { n } else { n - HEADER };
+			for i in (0..scan).take_while(|&i| start + (i as u64) < to) {
+				let Some(sample) = frame_header(&buf[i..n], block_size) else {
+					continue;
+				};
+				let mut frame = self.file.try_clone()?;
+				frame.seek(SeekFrom::Start(start + i as u64))?;
+				if let Ok(Some(_)) =
+					FrameReader::new(BufferedReader::new(frame)).read_next_or_eof(Vec::new())
+				{
+					return Ok(Some((start + i as u64, sample)));
+				}
+			}
+
+			if eof {
+				break;
+			}
+			start += scan as u64;
+		}
+
+		Ok(None)
+	}
+
+	/// Continues decoding at the frame that contains sample `pos`, so the next block may start a little before it.
+	///
+	/// FLAC files don't need a seek table, so the frame is found by bisecting the file on frame headers.
+	pub(super) fn seek(&mut self, pos: u64) -> io::Result<()> {
+		let mut best = (self.audio_start, 0);
+		let (mut lo, mut hi) = (self.audio_start, self.len);
+		while pos > 0 && lo < hi {
+			let mid = lo + (hi - lo) / 2;
+			match self.find_frame(mid, hi)? {
+				Some((offset, sample)) if sample <= pos => {
+					best = (offset, sample);
+					lo = offset + 1;
+				}
+				_ => hi = mid,
+			}
+		}
+
+		self.reset(best.0, best.1)
+	}
+
+	pub fn format(&self) -> Format {
+		let info = self.info;
+		Format {
+			channels: info.channels as u16,
+			sample_rate: info.sample_rate,
+			bits_per_sample: info.bits_per_sample as u16,
+		}
+	}
+
+	pub fn samples(&self) -> Option<u64> {
+		self.info.samples
+	}
+
+	pub fn pictures(&self) -> &[Vec<u8>] {
+		&self.pictures
+	}
+
+	/// MD5 of the decoded audio from the stream info; all zeros if the encoder didn't compute it.
+	pub fn md5(&self) -> [u8; 16] {
+		self.info.md5sum
+	}
+
+	pub(super) fn read(&mut self, block: &mut Block) -> claxon::Result<bool> {
+		let buf = std::mem::take(&mut block.buf);
+		match self.frames.read_next_or_eof(buf)? {
+			None => Ok(false),
+			Some(b) => {
+				// claxon computes the position of a short final block from its own size, which is wrong for fixed block size streams
+				block.start = self.pos;
+				self.pos += u64::from(b.duration());
+				block.len = b.duration() as usize;
+				block.channels = b.channels() as usize;
+				block.buf = b.into_buffer();
+				Ok(true)
+			}
+		}
+	}
+}
+
+/// Samples per channel in every frame but the last.
+const BLOCK_SIZE: usize = 4096;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 8;
+/// Encoders stop using stereo decorrelation and prediction above this sample size, so residuals fit in 32 bits.
+const MAX_PREDICTED_BITS: u16 = 24;
+/// Room for editing tags without rewriting the file, like the reference encoder leaves.
+const PADDING_LEN: u32 = 4096;
+
+/// Encodes samples into a FLAC stream.
+///
+/// The STREAMINFO block, including the MD5 signature, is filled in by [`Encoder::finish`].
+pub struct Encoder<W: Write + Seek> {
+	w: W,
+	format: Format,
+	info_pos: u64,
+	/// Samples waiting to be encoded, one buffer per channel
+	pending: Vec<Vec<i32>>,
+	frame_no: u64,
+	samples: u64,
+	frame_sizes: Option<(u32, u32)>,
+	md5: Md5,
+	md5_buf: Vec<u8>,
+	bits: BitWriter,
+	/// Mid and side channels for stereo decorrelation
+	scratch: [Vec<i32>; 2],
+}
+
+fn block_header(w: &mut impl Write, kind: u8, last: bool, len: usize) -> io::Result<()> {
+	let len = u32::try_from(len)
+		.ok()
+		.filter(|&n| n < 1 << 24)
+		.ok_or_else(|| io::Error::other("FLAC metadata block is too large"))?;
+	let [_, a, b, c] = len.to_be_bytes();
+	w.write_all(&[kind | if last { 0x80 } else { 0 }, a, b, c])
+}
+
+fn vorbis_comment(tags: &[(String, String)]) -> Vec<u8> {
+	let vendor = concat!("hermes ", env!("CARGO_PKG_VERSION"));
+	let tags = dedup_tags(tags);
+
+	let mut buf = Vec::new();
+	buf.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+	buf.extend_from_slice(vendor.as_bytes());
+	buf.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+	for (k, v) in tags {
+		buf.extend_from_slice(&((k.len() + 1 + v.len()) as u32).to_le_bytes());
+		buf.extend_from_slice(k.as_bytes());
+		buf.push(b'=');
+		buf.extend_from_slice(v.as_bytes());
+	}
+
+	buf
+}
+
+impl<W: Write + Seek> Encoder<W> {
+	/// Writes the metadata blocks: tags as Vorbis comments, and `pictures` as `PICTURE` blocks.
+	pub fn new(
+		mut w: W,
+		format: Format,
+		tags: &[(String, String)],
+		pictures: &[Vec<u8>],
+	) -> io::Result<Self> {
+		if !(1..=8).contains(&format.channels) || !(4..=32).contains(&format.bits_per_sample) {
+			return Err(io::Error::other(
+				"the sample format can't be encoded to FLAC",
+			));
+		}
+
+		w.write_all(b"fLaC")?;
+		block_header(&mut w, STREAMINFO, false, 34)?;
+		let info_pos = w.stream_position()?;
+		w.write_all(&[0; 34])?;
+
+		let comment = vorbis_comment(tags);
+		block_header(&mut w, VORBIS_COMMENT, false, comment.len())?;
+		w.write_all(&comment)?;
+		for p in pictures {
+			block_header(&mut w, PICTURE, false, p.len())?;
+			w.write_all(p)?;
+		}
+		block_header(&mut w, PADDING, true, PADDING_LEN as usize)?;
+		w.write_all(&[0; PADDING_LEN as usize])?;
+
+		Ok(Self {
+			w,
+			format,
+			info_pos,
+			pending: vec![Vec::with_capacity(BLOCK_SIZE * 2); usize::from(format.channels)],
+			frame_no: 0,
+			samples: 0,
+			frame_sizes: None,
+			md5: Md5::new(),
+			md5_buf: Vec::new(),
+			bits: BitWriter::default(),
+			scratch: Default::default(),
+		})
+	}
+
+	/// Appends the samples of `block` in `range`.
+	pub fn write(&mut self, block: &Block, range: Range<usize>) -> io::Result<()> {
+		// The signature is computed over little-endian interleaved samples
+		let width = usize::from(self.format.bytes_per_sample());
+		self.md5_buf.clear();
+		block.write_le(range.clone(), width, &mut self.md5_buf);
+		self.md5.update(&self.md5_buf);
+
+		for (ch, buf) in self.pending.iter_mut().enumerate() {
+			buf.extend_from_slice(&block.channel(ch)[range.clone()]);
+		}
+		self.samples += range.len() as u64;
+
+		while self.pending[0].len() >= BLOCK_SIZE {
+			self.encode_frame(BLOCK_SIZE)?;
+		}
+		Ok(())
+	}
+
+	/// Encodes the remaining samples and fills in the STREAMINFO block.
+	pub fn finish(mut self) -> io::Result<W> {
+		let n = self.pending[0].len();
+		if n > 0 {
+			self.encode_frame(n)?;
+		}
+
+		let f = self.format;
+		let (min_frame, max_frame) = self.frame_sizes.unwrap_or_default();
+		let mut info = BitWriter::default();
+		info.put(16, BLOCK_SIZE as u64);
+		info.put(16, BLOCK_SIZE as u64);
+		info.put(24, u64::from(min_frame));
+		info.put(24, u64::from(max_frame));
+		info.put(20, u64::from(f.sample_rate));
+		info.put(3, u64::from(f.channels - 1));
+		info.put(5, u64::from(f.bits_per_sample - 1));
+		info.put(36, self.samples);
+		info.bytes.extend_from_slice(&self.md5.finalize_reset());
+
+		let end = self.w.stream_position()?;
+		self.w.seek(SeekFrom::Start(self.info_pos))?;
+		self.w.write_all(&info.bytes)?;
+		self.w.seek(SeekFrom::Start(end))?;
+		self.w.flush()?;
+
+		Ok(self.w)
+	}
+
+	fn encode_frame(&mut self, n: usize) -> io::Result<()> {
+		let f = self.format;
+		let bps = f.bits_per_sample;
+		let bits = &mut self.bits;
+		bits.bytes.clear();
+
+		// Pick how to store the channels: independently or as a stereo pair with a side channel
+		let pending = &self.pending;
+		let (assignment, subframes) = if f.channels == 2 && bps <= MAX_PREDICTED_BITS {
+			let (l, r) = (&pending[0][..n], &pending[1][..n]);
+			let [mid, side] = &mut self.scratch;
+			mid.clear();
+			side.clear();
+			for (&l, &r) in l.iter().zip(r) {
+				mid.push((l + r) >> 1);
+				side.push(l - r);
+			}
+
+			let (side, mid) = (side.as_slice(), mid.as_slice());
+			let (sub_l, sub_r) = (analyze(l, bps), analyze(r, bps));
+			let (sub_m, sub_s) = (analyze(mid, bps), analyze(side, bps + 1));
+			let assignment = [
+				(1, sub_l.cost + sub_r.cost),
+				(8, sub_l.cost + sub_s.cost),
+				(9, sub_s.cost + sub_r.cost),
+				(10, sub_m.cost + sub_s.cost),
+			]
+			.into_iter()
+			.min_by_key(|&(_, cost)| cost)
+			.unwrap()
+			.0;
+
+			// The side channel needs an extra bit
+			let subs = match assignment {
+				1 => vec![(l, bps, sub_l), (r, bps, sub_r)],
+				8 => vec![(l, bps, sub_l), (side, bps + 1, sub_s)],
+				9 => vec![(side, bps + 1, sub_s), (r, bps, sub_r)],
+				_ => vec![(mid, bps, sub_m), (side, bps + 1, sub_s)],
+			};
+			(assignment, subs)
+		} else {
+			let subs = pending
+				.iter()
+				.map(|ch| (&ch[..n], bps, analyze(&ch[..n], bps)))
+				.collect::<Vec<_>>();
+			(u64::from(f.channels - 1), subs)
+		};
+
+		// Frame header; the blocking strategy bit is 0 for fixed-size blocks
+		bits.put(16, 0xfff8);
+		let (size_code, size_extra) = match n {
+			BLOCK_SIZE => (12, None),
+			1..=256 => (6, Some((8, n - 1))),
+			_ => (7, Some((16, n - 1))),
+		};
+		bits.put(4, size_code);
+		bits.put(4, sample_rate_code(f.sample_rate));
+		bits.put(4, assignment);
+		bits.put(3, sample_size_code(bps));
+		bits.put(1, 0);
+		put_utf8(bits, self.frame_no);
+		if let Some((width, v)) = size_extra {
+			bits.put(width, v as u64);
+		}
+		let crc = crc8(&bits.bytes);
+		bits.put(8, u64::from(crc));
+
+		for (samples, bps, sub) in &subframes {
+			write_subframe(bits, samples, *bps, sub);
+		}
+		bits.align();
+		let crc = crc16(&bits.bytes);
+		bits.put(16, u64::from(crc));
+
+		self.w.write_all(&bits.bytes)?;
+		let size = bits.bytes.len() as u32;
+		self.frame_sizes = Some(match self.frame_sizes {
+			None => (size, size),
+			Some((min, max)) => (min.min(size), max.max(size)),
+		});
+		self.frame_no += 1;
+		for buf in &mut self.pending {
+			buf.drain(..n);
+		}
+
+		Ok(())
+	}
+}
+
+fn sample_rate_code(rate: u32) -> u64 {
+	match rate {
+		88200 => 1,
+		176400 => 2,
+		192000 => 3,
+		8000 => 4,
+		16000 => 5,
+		22050 => 6,
+		24000 => 7,
+		32000 => 8,
+		44100 => 9,
+		48000 => 10,
+		96000 => 11,
+		// Read from STREAMINFO
+		_ => 0,
+	}
+}
+
+fn sample_size_code(bps: u16) -> u64 {
+	match bps {
+		8 => 1,
+		12 => 2,
+		16 => 4,
+		20 => 5,
+		24 => 6,
+		// Read from STREAMINFO
+		_ => 0,
+	}
+}
+
+// Frame numbers are stored with the same variable-length coding as UTF-8, extended to 36 bits.
+fn put_utf8(bits: &mut BitWriter, n: u64) {
+	if n < 0x80 {
+		bits.put(8, n);
+		return;
+	}
+
+	let len = (2..=7).find(|len| n < 1 << (5 * len + 1)).unwrap_or(7);
+	let lead = (0xff00_u64 >> len) & 0xff;
+	bits.put(8, lead | (n >> (6 * (len - 1))));
+	for i in (0..len - 1).rev() {
+		bits.put(8, 0x80 | ((n >> (6 * i)) & 0x3f));
+	}
+}
+
+#[derive(Debug)]
+enum Kind {
+	Constant,
+	Verbatim,
+	Fixed {
+		order: usize,
+		partition_order: u32,
+		params: Vec<u32>,
+	},
+}
+
+#[derive(Debug)]
+struct Subframe {
+	kind: Kind,
+	/// Estimated size in bits
+	cost: u64,
+}
+
+fn fixed_residual(x: &[i32], order: usize, i: usize) -> i64 {
+	let x = |j: usize| i64::from(x[i - j]);
+	match order {
+		0 => x(0),
+		1 => x(0) - x(1),
+		2 => x(0) - 2 * x(1) + x(2),
+		3 => x(0) - 3 * x(1) + 3 * x(2) - x(3),
+		_ => x(0) - 4 * x(1) + 6 * x(2) - 4 * x(3) + x(4),
+	}
+}
+
+fn zigzag(r: i64) -> u64 {
+	((r << 1) ^ (r >> 63)) as u64
+}
+
+fn analyze(x: &[i32], bps: u16) -> Subframe {
+	let n = x.len();
+	let header = 8;
+	let verbatim = Subframe {
+		kind: Kind::Verbatim,
+		cost: header + n as u64 * u64::from(bps),
+	};
+
+	if x.iter().all(|&s| s == x[0]) {
+		return Subframe {
+			kind: Kind::Constant,
+			cost: header + u64::from(bps),
+		};
+	}
+	if bps > MAX_PREDICTED_BITS + 1 || n <= MAX_FIXED_ORDER {
+		return verbatim;
+	}
+
+	// Choose the predictor order with the smallest total residual
+	let mut sums = [0_u64; MAX_FIXED_ORDER + 1];
+	for i in MAX_FIXED_ORDER..n {
+		for (order, sum) in sums.iter_mut().enumerate() {
+			*sum += fixed_residual(x, order, i).unsigned_abs();
+		}
+	}
+	let order = (0..=MAX_FIXED_ORDER).min_by_key(|&o| sums[o]).unwrap();
+
+	let residual = (order..n)
+		.map(|i| zigzag(fixed_residual(x, order, i)))
+		.collect::<Vec<_>>();
+	let (partition_order, params, rice_cost) = rice_partitions(&residual, n, order);
+
+	let cost = header + order as u64 * u64::from(bps) + 6 + rice_cost;
+	if cost < verbatim.cost {
+		Subframe {
+			kind: Kind::Fixed {
+				order,
+				partition_order,
+				params,
+			},
+			cost,
+		}
+	} else {
+		verbatim
+	}
+}
+
+// Estimates the bits needed for `count` values summing to `sum` with Rice parameter `k`.
+fn rice_cost(count: u64, sum: u64, k: u32) -> u64 {
+	count * (u64::from(k) + 1) + (sum >> k)
+}
+
+fn best_param(count: u64, sum: u64) -> (u32, u64) {
+	(0..=30)
+		.map(|k| (k, rice_cost(count, sum, k)))
+		.min_by_key(|&(_, c)| c)
+		.unwrap()
+}
+
+// Partition `p` of the residual; the first partition is shorter by the predictor order.
+fn partition(residual: &[u64], n: usize, order: usize, p: u32, j: usize) -> &[u64] {
+	let size = n >> p;
+	let start = if j == 0 { 0 } else { j * size - order };
+	&residual[start..(j + 1) * size - order]
+}
+
+fn rice_partitions(residual: &[u64], n: usize, order: usize) -> (u32, Vec<u32>, u64) {
+	let max_p = (0..=MAX_PARTITION_ORDER)
+		.take_while(|&p| n.trailing_zeros() >= p && (n >> p) > order)
+		.last()
+		.unwrap_or(0);
+
+	(0..=max_p)
+		.map(|p| {
+			let mut cost = 0;
+			let params = (0..1 << p)
+				.map(|j| {
+					let part = partition(residual, n, order, p, j);
+					let (k, c) = best_param(part.len() as u64, part.iter().sum());
+					cost += 5 + c;
+					k
+				})
+				.collect::<Vec<_>>();
+			(p, params, cost)
+		})
+		.min_by_key(|(_, _, cost)| *cost)
+		.unwrap()
+}
+
+fn write_subframe(bits: &mut BitWriter, x: &[i32], bps: u16, sub: &Subframe) {
+	let bps = u32::from(bps);
+	match &sub.kind {
+		Kind::Constant => {
+			bits.put(8, 0);
+			bits.put_signed(bps, x[0]);
+		}
+		Kind::Verbatim => {
+			bits.put(8, 0b10);
+			for &s in x {
+				bits.put_signed(bps, s);
+			}
+		}
+		Kind::Fixed {
+			order,
+			partition_order,
+			params,
+		} => {
+			bits.put(8, (0b1000 | *order as u64) << 1);
+			for &s in &x[..*order] {
+				bits.put_signed(bps, s);
+			}
+
+			// Method 1 has 5-bit parameters, for residuals that don't fit 4 bits
+			let wide = params.iter().any(|&k| k > 14);
+			bits.put(2, u64::from(wide));
+			bits.put(4, u64::from(*partition_order));
+			let residual = (*order..x.len())
+				.map(|i| zigzag(fixed_residual(x, *order, i)))
+				.collect::<Vec<_>>();
+			for (j, &k) in params.iter().enumerate() {
+				bits.put(if wide { 5 } else { 4 }, u64::from(k));
+				for &u in partition(&residual, x.len(), *order, *partition_order, j) {
+					bits.put_unary(u >> k);
+					bits.put(k, u & ((1 << k) - 1));
+				}
+			}
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+struct BitWriter {
+	bytes: Vec<u8>,
+	acc: u64,
+	n: u32,
+}
+
+impl BitWriter {
+	/// Writes the low `bits` bits of `v`, most significant first; `bits` is at most 36.
+	fn put(&mut self, bits: u32, v: u64) {
+		if bits == 0 {
+			return;
+		}
+		self.acc = (self.acc << bits) | (v & ((1 << bits) - 1));
+		self.n += bits;
+		while self.n >= 8 {
+			self.n -= 8;
+			self.bytes.push((self.acc >> self.n) as u8);
+		}
+	}
+
+	fn put_signed(&mut self, bits: u32, v: i32) {
+		self.put(bits, v as u64);
+	}
+
+	fn put_unary(&mut self, mut q: u64) {
+		while q >= 32 {
+			self.put(32, 0);
+			q -= 32;
+		}
+		self.put(q as u32 + 1, 1);
+	}
+
+	fn align(&mut self) {
+		if self.n > 0 {
+			self.put(8 - self.n, 0);
+		}
+	}
+}
+
+const fn crc_table<const N: u32>(poly: u32) -> [u32; 256] {
+	let mut table = [0; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = (i as u32) << (N - 8);
+		let mut bit = 0;
+		while bit < 8 {
+			crc = if crc & (1 << (N - 1)) != 0 {
+				(crc << 1) ^ poly
+			} else {
+				crc << 1
+			};
+			bit += 1;
+		}
+		table[i] = crc & ((1 << N) - 1);
+		i += 1;
+	}
+	table
+}
+
+static CRC8: [u32; 256] = crc_table::<8>(0x07);
+static CRC16: [u32; 256] = crc_table::<16>(0x8005);
+
+fn crc8(data: &[u8]) -> u8 {
+	data.iter()
+		.fold(0, |crc, &b| CRC8[usize::from(crc ^ b)] as u8)
+}
+
+fn crc16(data: &[u8]) -> u16 {
+	data.iter().fold(0, |crc, &b| {
+		(crc << 8) ^ CRC16[usize::from((crc >> 8) as u8 ^ b)] as u16
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		env,
+		fs,
+		io::Cursor,
+		process,
+	};
+
+	use super::*;
+
+	// Deterministic noise spanning the whole range of `bps` bit samples.
+	fn noise(n: usize, bps: u16, seed: u64) -> Vec<i32> {
+		let mut x = seed;
+		(0..n)
+			.map(|_| {
+				x = x
+					.wrapping_mul(6364136223846793005)
+					.wrapping_add(1442695040888963407);
+				((x >> 32) as i32) >> (32 - bps)
+			})
+			.collect()
+	}
+
+	fn sine(n: usize, bps: u16, period: f64) -> Vec<i32> {
+		let amp = f64::from((1 << (bps - 1)) - 1) * 0.8;
+		(0..n)
+			.map(|i| (amp * (i as f64 * std::f64::consts::TAU / period).sin()) as i32)
+			.collect()
+	}
+
+	fn block(channels: &[Vec<i32>]) -> Block {
+		Block {
+			start: 0,
+			len: channels[0].len(),
+			channels: channels.len(),
+			buf: channels.concat(),
+		}
+	}
+
+	// Encodes the channels in uneven chunks, so frames are cut across calls to `write`.
+	fn encode(bps: u16, channels: &[Vec<i32>]) -> Vec<u8> {
+		let format = Format {
+			channels: channels.len() as u16,
+			sample_rate: 44100,
+			bits_per_sample: bps,
+		};
+		let tags = [("TITLE".to_string(), "Test".to_string())];
+		let mut enc = Encoder::new(Cursor::new(Vec::new()), format, &tags, &[]).unwrap();
+		let block = block(channels);
+		let mut start = 0;
+		while start < block.len {
+			let end = (start + 1000).min(block.len);
+			enc.write(&block, start..end).unwrap();
+			start = end;
+		}
+		enc.finish().unwrap().into_inner()
+	}
+
+	// Decodes the stream with claxon and checks it against the input and the MD5 of the input.
+	fn assert_round_trip(bps: u16, channels: &[Vec<i32>]) -> Vec<u8> {
+		let data = encode(bps, channels);
+		let mut reader = claxon::FlacReader::new(Cursor::new(&data)).unwrap();
+		let info = reader.streaminfo();
+		assert_eq!(u32::from(bps), info.bits_per_sample);
+		assert_eq!(channels.len() as u32, info.channels);
+		assert_eq!(Some(channels[0].len() as u64), info.samples);
+		assert_eq!(reader.tags().collect::<Vec<_>>(), [("TITLE", "Test")]);
+
+		let decoded = reader.samples().collect::<Result<Vec<_>, _>>().unwrap();
+		let interleaved = (0..channels[0].len())
+			.flat_map(|i| channels.iter().map(move |ch| ch[i]))
+			.collect::<Vec<_>>();
+		assert!(
+			decoded == interleaved,
+			"decoded samples differ at {bps} bits"
+		);
+
+		let mut le = Vec::new();
+		block(channels).write_le(0..channels[0].len(), usize::from(bps.div_ceil(8)), &mut le);
+		assert_eq!(info.md5sum, <[u8; 16]>::from(Md5::digest(&le)));
+
+		data
+	}
+
+	// The channel assignment of the first frame: 0-7 are independent channels, 8-10 use a side channel.
+	fn first_assignment(data: &[u8]) -> u8 {
+		let mut pos = 4;
+		loop {
+			let header = &data[pos..pos + 4];
+			pos += 4 + u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+			if header[0] & 0x80 != 0 {
+				break;
+			}
+		}
+		assert_eq!(data[pos..pos + 2], [0xff, 0xf8]);
+		data[pos + 3] >> 4
+	}
+
+	#[test]
+	fn mono() {
+		let n = BLOCK_SIZE * 2 + 100;
+		for bps in [8, 16, 24] {
+			assert_round_trip(bps, &[sine(n, bps, 100.0)]);
+			assert_round_trip(bps, &[noise(n, bps, 1)]);
+		}
+	}
+
+	#[test]
+	fn stereo_side_channels() {
+		let n = BLOCK_SIZE * 3;
+		for bps in [8, 16, 24] {
+			let smooth = sine(n, bps, 250.0);
+			let small = noise(n, 4, 2);
+			let with_noise = |f: fn(i32, i32) -> i32| {
+				smooth
+					.iter()
+					.zip(&small)
+					.map(|(&x, &e)| f(x, e))
+					.collect::<Vec<_>>()
+			};
+			let noisy = with_noise(|x, e| x - e);
+			// Odd sums make the mid channel lose a bit that the side channel restores
+			let up = with_noise(|x, e| x + e / 2);
+			let down = with_noise(|x, e| x - e / 2 - 1);
+			// Opposite channels starting near full scale: the side channel needs the extra bit
+			let peak = sine(n + 62, bps, 250.0).split_off(62);
+			let inverted = peak.iter().map(|x| -x).collect::<Vec<_>>();
+
+			for (l, r, assignment) in [
+				(&smooth, &noisy, 8),
+				(&noisy, &smooth, 9),
+				(&up, &down, 10),
+				(&peak, &inverted, 10),
+			] {
+				let data = assert_round_trip(bps, &[l.clone(), r.clone()]);
+				assert_eq!(first_assignment(&data), assignment, "at {bps} bits");
+			}
+
+			// Independent channels
+			let data = assert_round_trip(bps, &[noise(n, bps, 3), sine(n, bps, 40.0)]);
+			assert_eq!(first_assignment(&data), 1);
+		}
+	}
+
+	#[test]
+	fn short_final_block() {
+		for n in [1, 5, 300, BLOCK_SIZE - 1, BLOCK_SIZE + 1] {
+			assert_round_trip(16, &[sine(n, 16, 60.0), noise(n, 16, 4)]);
+		}
+	}
+
+	#[test]
+	fn constant_and_verbatim_subframes() {
+		let n = BLOCK_SIZE;
+		let silence = vec![0; n];
+		let dc = vec![-1234; n];
+		assert!(matches!(analyze(&silence, 16).kind, Kind::Constant));
+		assert!(matches!(analyze(&dc, 16).kind, Kind::Constant));
+		// Full-scale noise can't be predicted
+		let white = noise(n, 16, 5);
+		assert!(matches!(analyze(&white, 16).kind, Kind::Verbatim));
+		assert!(matches!(
+			analyze(&sine(n, 16, 100.0), 16).kind,
+			Kind::Fixed { .. }
+		));
+
+		assert_round_trip(16, &[silence.clone(), dc.clone()]);
+		assert_round_trip(16, &[white, silence]);
+		assert_round_trip(24, &[dc, noise(n, 24, 6)]);
+		// Frames too short to predict are stored verbatim
+		assert!(matches!(analyze(&[1, 2, 3], 16).kind, Kind::Verbatim));
+		assert_round_trip(16, &[vec![1, 2, 3]]);
+	}
+
+	#[test]
+	fn seeking_decodes_only_from_the_frame_of_the_target() {
+		let n = BLOCK_SIZE * 40 + 123;
+		// Noise is stored verbatim, so the frames are full of bytes that look like sync codes
+		let channels = [noise(n, 16, 7), sine(n, 16, 90.0)];
+		let path = env::temp_dir().join(format!("hermes-flac-seek-{}.flac", process::id()));
+		fs::write(&path, encode(16, &channels)).unwrap();
+		let reader = Reader::open(&path);
+		fs::remove_file(&path).unwrap();
+		let mut reader = reader.unwrap();
+
+		let bs = BLOCK_SIZE as u64;
+		let mut block = Block::default();
+		for (target, frame) in [(20 * bs + 100, 20), (bs * 40 + 5, 40), (bs - 1, 0), (3, 0)] {
+			reader.seek(target).unwrap();
+			assert!(reader.read(&mut block).unwrap());
+			assert_eq!(block.start, frame * bs, "seeking to {target}");
+			let i = (target - block.start) as usize;
+			assert_eq!(block.buf[i], channels[0][target as usize]);
+			assert_eq!(block.buf[block.len + i], channels[1][target as usize]);
+		}
+
+		// A chunk in the middle decodes its own frames and no others
+		let range = 20 * bs + 100..25 * bs;
+		reader.seek(range.start).unwrap();
+		let mut frames = 0;
+		while reader.read(&mut block).unwrap() {
+			frames += 1;
+			if block.start + block.len as u64 >= range.end {
+				break;
+			}
+		}
+		assert_eq!(frames, 5);
+	}
+}
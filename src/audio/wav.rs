@@ -0,0 +1,444 @@
+use std::{
+	collections::BTreeMap,
+	fs::File,
+	io::{
+		self,
+		Read,
+		Seek,
+		SeekFrom,
+		Write,
+	},
+	ops::Range,
+	path::Path,
+};
+
+use super::{
+	dedup_tags,
+	malformed,
+	Block,
+	Format,
+	Pcm,
+};
+use crate::{
+	Error,
+	Result,
+};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+// Reads a chunk header: the 4 byte id and the size of the body.
+fn chunk_header(f: &mut File, big_endian: bool) -> io::Result<Option<([u8; 4], u32)>> {
+	let mut buf = [0; 8];
+	if super::read_full(f, &mut buf)? < buf.len() {
+		return Ok(None);
+	}
+	let size = [buf[4], buf[5], buf[6], buf[7]];
+	let size = if big_endian {
+		u32::from_be_bytes(size)
+	} else {
+		u32::from_le_bytes(size)
+	};
+
+	Ok(Some(([buf[0], buf[1], buf[2], buf[3]], size)))
+}
+
+// Chunks are padded to an even size.
+fn skip_chunk(f: &mut File, size: u32) -> io::Result<()> {
+	f.seek(SeekFrom::Current(i64::from(size) + i64::from(size & 1)))
+		.map(|_| ())
+}
+
+// The data chunk of a file still being written may declare a size past the end of the file.
+fn clamp_data(f: &File, start: u64, len: u64) -> io::Result<u64> {
+	let file_len = f.metadata()?.len();
+	Ok(len.min(file_len.saturating_sub(start)))
+}
+
+/// Reads the chunks of a RIFF WAVE file after the 12 byte header.
+pub(super) fn read_riff(f: &mut File, path: &Path) -> Result<Pcm> {
+	let io_err = |e| Error::io(path, e);
+	let mut fmt = None;
+
+	while let Some((id, size)) = chunk_header(f, false).map_err(io_err)? {
+		match &id {
+			b"fmt " => {
+				if size < 16 {
+					return Err(malformed(path, "the fmt chunk is too short"));
+				}
+				let mut body = vec![0; size as usize];
+				f.read_exact(&mut body).map_err(io_err)?;
+				if size & 1 == 1 {
+					f.seek(SeekFrom::Current(1)).map_err(io_err)?;
+				}
+
+				let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+				let tag = u16_at(0);
+				// The sub format GUID of WAVE_FORMAT_EXTENSIBLE starts with the format tag
+				let sub_tag = (tag == WAVE_FORMAT_EXTENSIBLE && body.len() >= 26).then(|| u16_at(24));
+				if tag != WAVE_FORMAT_PCM && sub_tag != Some(WAVE_FORMAT_PCM) {
+					return Err(malformed(path, "only uncompressed PCM WAV files are supported"));
+				}
+
+				let format = Format {
+					channels: u16_at(2),
+					sample_rate: u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+					bits_per_sample: u16_at(14),
+				};
+				if format.channels == 0
+					|| format.sample_rate == 0
+					|| !(1..=32).contains(&format.bits_per_sample)
+				{
+					return Err(malformed(path, "the fmt chunk is invalid"));
+				}
+				if u16_at(12) != format.block_align() {
+					return Err(malformed(path, "the block alignment doesn't match the sample format"));
+				}
+
+				fmt = Some((format, body));
+			}
+			b"data" => {
+				let Some((format, fmt_chunk)) = fmt else {
+					return Err(malformed(path, "the data chunk comes before the fmt chunk"));
+				};
+				let data_start = f.stream_position().map_err(io_err)?;
+				return Ok(Pcm {
+					format,
+					fmt_chunk,
+					big_endian: false,
+					signed_8bit: false,
+					data_start,
+					data_len: clamp_data(f, data_start, u64::from(size)).map_err(io_err)?,
+				});
+			}
+			_ => skip_chunk(f, size).map_err(io_err)?,
+		}
+	}
+
+	Err(malformed(path, "the file has no data chunk"))
+}
+
+// Converts an 80-bit IEEE 754 extended precision number, as used for the AIFF sample rate.
+fn extended_to_u32(b: [u8; 10]) -> Option<u32> {
+	let exp = i32::from(u16::from_be_bytes([b[0], b[1]]) & 0x7fff) - 16383;
+	let mantissa = u64::from_be_bytes([b[2], b[3], b[4], b[5], b[6], b[7], b[8], b[9]]);
+	if b[0] & 0x80 != 0 || !(0..32).contains(&exp) {
+		return None;
+	}
+
+	u32::try_from(mantissa >> (63 - exp)).ok()
+}
+
+/// Reads the chunks of an AIFF or AIFF-C file after the 12 byte header.
+pub(super) fn read_aiff(f: &mut File, path: &Path, aifc: bool) -> Result<Pcm> {
+	let io_err = |e| Error::io(path, e);
+	let mut comm = None;
+	let mut ssnd = None;
+
+	while let Some((id, size)) = chunk_header(f, true).map_err(io_err)? {
+		match &id {
+			b"COMM" => {
+				if size < 18 || (aifc && size < 22) {
+					return Err(malformed(path, "the COMM chunk is too short"));
+				}
+				let mut body = vec![0; size as usize];
+				f.read_exact(&mut body).map_err(io_err)?;
+				if size & 1 == 1 {
+					f.seek(SeekFrom::Current(1)).map_err(io_err)?;
+				}
+
+				let little_endian = if aifc {
+					match &body[18..22] {
+						b"NONE" | b"twos" => false,
+						b"sowt" => true,
+						_ => {
+							return Err(malformed(
+								path,
+								"only uncompressed PCM AIFF-C files are supported",
+							))
+						}
+					}
+				} else {
+					false
+				};
+
+				let rate = body[8..18].try_into().ok().and_then(extended_to_u32);
+				let format = Format {
+					channels: u16::from_be_bytes([body[0], body[1]]),
+					sample_rate: rate.unwrap_or(0),
+					bits_per_sample: u16::from_be_bytes([body[6], body[7]]),
+				};
+				if format.channels == 0
+					|| format.sample_rate == 0
+					|| !(1..=32).contains(&format.bits_per_sample)
+				{
+					return Err(malformed(path, "the COMM chunk is invalid"));
+				}
+
+				comm = Some((format, little_endian));
+			}
+			b"SSND" => {
+				if size < 8 {
+					return Err(malformed(path, "the SSND chunk is too short"));
+				}
+				let body_start = f.stream_position().map_err(io_err)?;
+				// The offset to the first sample, followed by the block size which nobody uses
+				let mut header = [0; 8];
+				f.read_exact(&mut header).map_err(io_err)?;
+				let offset = u64::from(u32::from_be_bytes([header[0], header[1], header[2], header[3]]));
+
+				let start = body_start + 8 + offset;
+				let len = u64::from(size).saturating_sub(8 + offset);
+				ssnd = Some((start, clamp_data(f, start, len).map_err(io_err)?));
+
+				let next = body_start + u64::from(size) + u64::from(size & 1);
+				f.seek(SeekFrom::Start(next)).map_err(io_err)?;
+			}
+			_ => skip_chunk(f, size).map_err(io_err)?,
+		}
+	}
+
+	let Some((format, little_endian)) = comm else {
+		return Err(malformed(path, "the file has no COMM chunk"));
+	};
+	let Some((data_start, data_len)) = ssnd else {
+		return Err(malformed(path, "the file has no SSND chunk"));
+	};
+
+	Ok(Pcm {
+		format,
+		fmt_chunk: pcm_fmt_chunk(format),
+		big_endian: !little_endian,
+		signed_8bit: true,
+		data_start,
+		data_len,
+	})
+}
+
+/// Returns the body of a `fmt ` chunk describing plain PCM audio.
+pub fn pcm_fmt_chunk(format: Format) -> Vec<u8> {
+	let mut buf = Vec::with_capacity(16);
+	buf.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+	buf.extend_from_slice(&format.channels.to_le_bytes());
+	buf.extend_from_slice(&format.sample_rate.to_le_bytes());
+	let byte_rate = format.sample_rate * u32::from(format.block_align());
+	buf.extend_from_slice(&byte_rate.to_le_bytes());
+	buf.extend_from_slice(&format.block_align().to_le_bytes());
+	// WAV samples are padded to whole bytes
+	buf.extend_from_slice(&(format.bytes_per_sample() * 8).to_le_bytes());
+	buf
+}
+
+// Maps tags to RIFF INFO chunk ids, the same way ffmpeg does.
+fn info_id(key: &str) -> Option<&'static [u8; 4]> {
+	const IDS: &[(&str, &[u8; 4])] = &[
+		("TITLE", b"INAM"),
+		("ARTIST", b"IART"),
+		("ALBUM", b"IPRD"),
+		("DATE", b"ICRD"),
+		("GENRE", b"IGNR"),
+		("COMMENT", b"ICMT"),
+		("COPYRIGHT", b"ICOP"),
+		("TRACKNUMBER", b"IPRT"),
+		("SONGWRITER", b"IMUS"),
+		("COMPOSER", b"IMUS"),
+	];
+
+	IDS.iter()
+		.find(|(k, _)| k.eq_ignore_ascii_case(key))
+		.map(|(_, id)| *id)
+}
+
+// Builds the body of a LIST chunk holding INFO tags.
+fn info_list(tags: &[(String, String)]) -> Vec<u8> {
+	let mut info = BTreeMap::new();
+	for (k, v) in dedup_tags(tags) {
+		if let Some(id) = info_id(k) {
+			info.insert(id, v);
+		}
+	}
+	if info.is_empty() {
+		return Vec::new();
+	}
+
+	let mut buf = b"INFO".to_vec();
+	for (id, v) in info {
+		// Values are NUL-terminated and padded to an even size
+		let size = v.len() + 1;
+		buf.extend_from_slice(id);
+		buf.extend_from_slice(&(size as u32).to_le_bytes());
+		buf.extend_from_slice(v.as_bytes());
+		buf.push(0);
+		if size & 1 == 1 {
+			buf.push(0);
+		}
+	}
+
+	buf
+}
+
+/// Decodes a little-endian WAV sample of `bits` significant bits.
+pub fn decode_sample(b: &[u8], bits: u16) -> i32 {
+	let width = b.len() as u32 * 8;
+	let raw = match *b {
+		// 8-bit WAV samples are unsigned
+		[x] => i32::from(x) - 128,
+		[a, b] => i32::from(i16::from_le_bytes([a, b])),
+		[a, b, c] => i32::from_le_bytes([0, a, b, c]) >> 8,
+		[a, b, c, d] => i32::from_le_bytes([a, b, c, d]),
+		_ => unreachable!("samples are at most 32 bits"),
+	};
+
+	// Samples narrower than their container are stored in the high bits
+	raw >> (width - u32::from(bits))
+}
+
+fn encode_sample(s: i32, bits: u16, width: usize, out: &mut Vec<u8>) {
+	let s = s << (width as u32 * 8 - u32::from(bits));
+	match width {
+		1 => out.push((s + 128) as u8),
+		_ => out.extend_from_slice(&s.to_le_bytes()[..width]),
+	}
+}
+
+/// Writes a WAV file as samples come in, filling in the chunk sizes in [`Writer::finish`].
+pub struct Writer<W: Write + Seek> {
+	w: W,
+	format: Format,
+	riff_start: u64,
+	data_start: u64,
+	data_len: u64,
+	bytes: Vec<u8>,
+}
+
+impl<W: Write + Seek> Writer<W> {
+	/// Writes the header; tags go in a RIFF INFO list.
+	pub fn new(
+		mut w: W,
+		fmt_chunk: &[u8],
+		format: Format,
+		tags: &[(String, String)],
+	) -> io::Result<Self> {
+		let riff_start = w.stream_position()?;
+		w.write_all(b"RIFF\0\0\0\0WAVE")?;
+
+		let mut chunk = |id: &[u8; 4], body: &[u8]| -> io::Result<()> {
+			w.write_all(id)?;
+			w.write_all(&(body.len() as u32).to_le_bytes())?;
+			w.write_all(body)?;
+			if body.len() & 1 == 1 {
+				w.write_all(&[0])?;
+			}
+			Ok(())
+		};
+		chunk(b"fmt ", fmt_chunk)?;
+		let list = info_list(tags);
+		if !list.is_empty() {
+			chunk(b"LIST", &list)?;
+		}
+
+		w.write_all(b"data\0\0\0\0")?;
+		let data_start = w.stream_position()?;
+
+		Ok(Self {
+			w,
+			format,
+			riff_start,
+			data_start,
+			data_len: 0,
+			bytes: Vec::new(),
+		})
+	}
+
+	/// Appends the samples of `block` in `range`.
+	pub fn write(&mut self, block: &Block, range: Range<usize>) -> io::Result<()> {
+		let width = usize::from(self.format.bytes_per_sample());
+		self.bytes.clear();
+		for i in range {
+			for ch in 0..block.channels {
+				encode_sample(
+					block.channel(ch)[i],
+					self.format.bits_per_sample,
+					width,
+					&mut self.bytes,
+				);
+			}
+		}
+
+		self.data_len += self.bytes.len() as u64;
+		self.w.write_all(&self.bytes)
+	}
+
+	/// Pads the data chunk and fills in the chunk sizes.
+	pub fn finish(mut self) -> io::Result<W> {
+		if self.data_len & 1 == 1 {
+			self.w.write_all(&[0])?;
+		}
+		let end = self.w.stream_position()?;
+		let too_large = || io::Error::other("the track is too large for a WAV file");
+		let riff_len = u32::try_from(end - self.riff_start - 8).map_err(|_| too_large())?;
+		let data_len = u32::try_from(self.data_len).map_err(|_| too_large())?;
+
+		self.w.seek(SeekFrom::Start(self.riff_start + 4))?;
+		self.w.write_all(&riff_len.to_le_bytes())?;
+		self.w.seek(SeekFrom::Start(self.data_start - 4))?;
+		self.w.write_all(&data_len.to_le_bytes())?;
+		self.w.seek(SeekFrom::Start(end))?;
+		self.w.flush()?;
+
+		Ok(self.w)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		env,
+		fs,
+		process,
+	};
+
+	use super::*;
+
+	// A mono WAV file declaring `bits` per sample with a matching block alignment.
+	fn wav_with_bits(bits: u16) -> Vec<u8> {
+		let align = bits.div_ceil(8);
+		let mut fmt = Vec::new();
+		fmt.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+		fmt.extend_from_slice(&1u16.to_le_bytes());
+		fmt.extend_from_slice(&44100u32.to_le_bytes());
+		fmt.extend_from_slice(&(44100 * u32::from(align)).to_le_bytes());
+		fmt.extend_from_slice(&align.to_le_bytes());
+		fmt.extend_from_slice(&bits.to_le_bytes());
+
+		let data = vec![0; usize::from(align) * 4];
+		let mut buf = b"RIFF".to_vec();
+		buf.extend_from_slice(&(4 + 8 + fmt.len() as u32 + 8 + data.len() as u32).to_le_bytes());
+		buf.extend_from_slice(b"WAVEfmt ");
+		buf.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+		buf.extend_from_slice(&fmt);
+		buf.extend_from_slice(b"data");
+		buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+		buf.extend_from_slice(&data);
+		buf
+	}
+
+	#[test]
+	fn rejects_samples_wider_than_32_bits() {
+		let path = env::temp_dir().join(format!("hermes-wav-bits-{}.wav", process::id()));
+		for (bits, ok) in [(16, true), (24, true), (32, true), (33, false), (64, false)] {
+			fs::write(&path, wav_with_bits(bits)).unwrap();
+			match Pcm::open(&path, "WAVE") {
+				Ok((_, pcm)) => {
+					assert!(ok, "{bits} bits were accepted");
+					assert_eq!(pcm.format.bits_per_sample, bits);
+				}
+				Err(e) => {
+					assert!(!ok, "{bits} bits were rejected: {e}");
+					assert!(matches!(e, Error::Format { .. }), "{e}");
+				}
+			}
+		}
+		fs::remove_file(&path).unwrap();
+	}
+}
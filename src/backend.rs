@@ -0,0 +1,95 @@
+mod ffmpeg;
+mod native;
+mod sox;
+
+use core::fmt::Debug;
+use std::{
+	ops::ControlFlow,
+	path::Path,
+	sync::Arc,
+	time::Duration,
+};
+
+pub use self::{
+	ffmpeg::Ffmpeg,
+	native::Native,
+	sox::Sox,
+};
+use crate::{
+	cue::FRAMES_PER_SEC,
+	plan::Job,
+	Result,
+	Warning,
+};
+
+// Formats a position in CD frames as seconds, with microsecond precision.
+fn frames_to_secs(frames: u64) -> String {
+	let sec = frames / FRAMES_PER_SEC;
+	let us = (frames % FRAMES_PER_SEC) * 1_000_000 / FRAMES_PER_SEC;
+
+	if us == 0 {
+		sec.to_string()
+	} else {
+		format!("{sec}.{us:06}")
+	}
+}
+
+/// Called with how far into the source a backend has progressed; returning [`ControlFlow::Break`] cancels the job.
+pub type Progress<'a> = dyn FnMut(Duration) -> ControlFlow<()> + 'a;
+
+/// Something that can cut, encode and tag the tracks of a [`Job`].
+pub trait Backend: Debug + Send + Sync {
+	/// A short name shown in messages.
+	fn name(&self) -> &str;
+
+	/// Returns an error if the backend can't run `job`, without doing any work.
+	///
+	/// This is called for every job while planning, so unsupported formats are caught before anything is split.
+	fn check(&self, job: &Job) -> Result<()>;
+
+	/// Returns problems with how `job` would be split that don't prevent it from running.
+	///
+	/// Like [`Backend::check`], this is called for every job while planning.
+	fn warnings(&self, job: &Job) -> Vec<Warning> {
+		let _ = job;
+		Vec::new()
+	}
+
+	/// Splits the source of `job` into its tracks, encoding and tagging each.
+	///
+	/// Output directories are created before this is called.
+	fn run(&self, job: &Job, progress: &mut Progress) -> Result<()>;
+}
+
+/// Backends that can be selected by name.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+	/// Use the ffmpeg executable
+	#[default]
+	Ffmpeg,
+	/// Split WAV, AIFF, FLAC and raw CD audio into WAV or FLAC files without external programs
+	Native,
+	/// Use the sox executable
+	Sox,
+}
+
+impl Kind {
+	/// The name used on the command line.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Ffmpeg => "ffmpeg",
+			Self::Native => "native",
+			Self::Sox => "sox",
+		}
+	}
+
+	/// Creates the backend; `ffmpeg` and `sox` are the paths to the executables.
+	pub fn create(self, ffmpeg: &Path, sox: &Path) -> Arc<dyn Backend> {
+		match self {
+			Self::Ffmpeg => Arc::new(Ffmpeg::new(ffmpeg)),
+			Self::Native => Arc::new(Native::new()),
+			Self::Sox => Arc::new(Sox::new(sox)),
+		}
+	}
+}
@@ -0,0 +1,363 @@
+use std::{
+	io::{
+		BufRead,
+		BufReader,
+	},
+	path::PathBuf,
+	process::{
+		Command,
+		Stdio,
+	},
+	time::Duration,
+};
+
+use super::{
+	frames_to_secs,
+	Backend,
+	Progress,
+};
+use crate::{
+	audio::Pcm,
+	cue::FRAMES_PER_SEC,
+	plan::{
+		Cut,
+		Encode,
+		Job,
+		Overwrite,
+	},
+	Error,
+	Result,
+	Warning,
+};
+
+/// Splits files by running an ffmpeg executable.
+///
+/// [`Encode::Copy`] copies the audio stream of lossy sources, which cuts tracks at packet boundaries.
+/// Lossless sources are re-encoded to the same codec instead, so cuts are sample-accurate.
+/// Packets are never partially re-encoded and no gapless metadata (encoder delay and padding) is written for lossy copies;
+/// such jobs get a [`Warning::InexactCopy`].
+#[derive(Debug, Clone)]
+pub struct Ffmpeg {
+	path: PathBuf,
+}
+
+impl Default for Ffmpeg {
+	fn default() -> Self {
+		Self::new("ffmpeg")
+	}
+}
+
+impl Ffmpeg {
+	/// `path` is the path to the ffmpeg executable.
+	pub fn new(path: impl Into<PathBuf>) -> Self {
+		Self { path: path.into() }
+	}
+
+	/// Builds the ffmpeg command that runs `job`.
+	pub fn command(&self, job: &Job) -> Command {
+		const COPY_ARGS: &[&str] = &["-c", "copy"];
+
+		let mut cmd = Command::new(&self.path);
+		let force_opt = match job.overwrite {
+			Overwrite::Ask => None,
+			Overwrite::Always => Some("-y"),
+			Overwrite::Never => Some("-n"),
+		};
+
+		// Seeking on the input lets jobs with later tracks of a disc skip decoding the earlier ones,
+		// and limiting its duration stops jobs with earlier tracks from decoding the rest
+		let offset = job.cuts.first().map_or(0, |c| c.start);
+		cmd.args(force_opt).args(["-loglevel", "error"]);
+		if offset > 0 {
+			cmd.args(["-ss", &frames_to_secs(offset)]);
+		}
+		// An input -to is a position in the source, so the end relative to -ss is given as a duration
+		if let Some(end) = job.cuts.last().and_then(|c| c.end) {
+			cmd.args(["-t", &frames_to_secs(end.saturating_sub(offset))]);
+		}
+		cmd.arg("-i").arg(&job.source);
+
+		// Stream copies can only be cut between packets, so lossless sources are re-encoded to the same codec instead
+		let lossless = match &job.encode {
+			Encode::Copy => lossless_args(job),
+			_ => None,
+		};
+		let (encode_args, custom_args) = match &job.encode {
+			Encode::Copy => (lossless.unwrap_or(COPY_ARGS), [].as_slice()),
+			Encode::Preset(p) => (p.ffmpeg_args(), [].as_slice()),
+			Encode::Custom(args) => ([].as_slice(), args.as_slice()),
+		};
+
+		// Stream copies don't decode anything, so seeking per output is cheap for them
+		let segments = match &job.encode {
+			Encode::Copy if lossless.is_none() => None,
+			_ => segment_filter(&job.cuts, offset),
+		};
+		// Mapping streams explicitly drops embedded cover art unless it's mapped too
+		let out_ext = match &job.encode {
+			Encode::Preset(p) => p.ext(),
+			Encode::Copy => source_ext(job),
+			Encode::Custom(_) => "",
+		};
+		let picture_args: &[&str] = if matches!(out_ext, "flac" | "mp3" | "m4a") {
+			&["-map", "0:v?", "-c:v", "copy"]
+		} else {
+			&[]
+		};
+
+		if let Some((filter, _)) = &segments {
+			cmd.arg("-filter_complex").arg(filter);
+		}
+
+		for (i, cut) in job.cuts.iter().enumerate() {
+			match &segments {
+				Some((_, outputs)) => {
+					cmd.arg("-map").arg(&outputs[i]).args(picture_args);
+				}
+				None => {
+					cmd.args(["-ss", &frames_to_secs(cut.start - offset)]).args(
+						cut.end
+							.map(|end| frames_to_secs(end - offset))
+							.into_iter()
+							.flat_map(|to| ["-to".to_string(), to]),
+					);
+				}
+			}
+
+			cmd.args(
+					cut.tags
+						.iter()
+						.flat_map(|(k, v)| ["-metadata".to_string(), format!("{k}={v}")]),
+				)
+				.args(encode_args)
+				.args(custom_args)
+				.arg(&cut.path);
+		}
+
+		cmd
+	}
+}
+
+fn source_ext(job: &Job) -> &str {
+	job.source
+		.extension()
+		.and_then(|s| s.to_str())
+		.unwrap_or_default()
+}
+
+// Encoder options that reproduce the audio of a lossless source exactly, or None if the codec is lossy or unknown.
+fn lossless_args(job: &Job) -> Option<&'static [&'static str]> {
+	let ext = source_ext(job);
+	if ext.eq_ignore_ascii_case("flac") {
+		Some(&["-c:a", "flac", "-compression_level", "8"])
+	} else if ext.eq_ignore_ascii_case("wav") {
+		// ffmpeg writes 16-bit WAV files unless told otherwise
+		let (_, pcm) = Pcm::open(&job.source, &job.kind).ok()?;
+		Some(match pcm.format.bits_per_sample {
+			0..=8 => &["-c:a", "pcm_u8"],
+			9..=16 => &["-c:a", "pcm_s16le"],
+			17..=24 => &["-c:a", "pcm_s24le"],
+			_ => &["-c:a", "pcm_s32le"],
+		})
+	} else {
+		None
+	}
+}
+
+// Builds a filter graph splitting the decoded audio with the asegment filter, so the source is decoded only once no matter how many tracks it has.
+//
+// Positions are relative to `offset`, where the input was seeked to; the input must end where the last cut does.
+// Returns the graph and the output label for every cut, or None if the cuts aren't consecutive or there's nothing to split.
+fn segment_filter(cuts: &[Cut], offset: u64) -> Option<(String, Vec<String>)> {
+	// Split points; segment n spans from point n - 1 (or the start of the input) to point n
+	let mut points = Vec::new();
+	let mut segments = Vec::with_capacity(cuts.len());
+
+	for (i, cut) in cuts.iter().enumerate() {
+		let last = points.last().copied().unwrap_or(offset);
+		if cut.start < last {
+			return None;
+		} else if cut.start > last {
+			// Audio before the track, such as a hidden pregap
+			points.push(cut.start);
+		}
+		segments.push(points.len());
+
+		match cut.end {
+			Some(end) if end <= cut.start => return None,
+			// The last segment runs to the end of the input
+			_ if i + 1 == cuts.len() => (),
+			Some(end) => points.push(end),
+			None => return None,
+		}
+	}
+
+	if points.is_empty() {
+		return None;
+	}
+
+	let timestamps = points
+		.iter()
+		.map(|&p| frames_to_secs(p - offset))
+		.collect::<Vec<_>>()
+		.join("|");
+	let mut graph = format!("[0:a:0]asegment=timestamps={timestamps}");
+	for n in 0..=points.len() {
+		graph += &format!("[s{n}]");
+	}
+
+	// Each track should start at timestamp 0; unused segments, like gaps between tracks, must still be consumed
+	for n in 0..=points.len() {
+		if segments.contains(&n) {
+			graph += &format!(";[s{n}]asetpts=PTS-STARTPTS[t{n}]");
+		} else {
+			graph += &format!(";[s{n}]anullsink");
+		}
+	}
+
+	let outputs = segments.iter().map(|n| format!("[t{n}]")).collect();
+	Some((graph, outputs))
+}
+
+impl Backend for Ffmpeg {
+	fn name(&self) -> &str {
+		"ffmpeg"
+	}
+
+	fn check(&self, _job: &Job) -> Result<()> {
+		Ok(())
+	}
+
+	fn warnings(&self, job: &Job) -> Vec<Warning> {
+		match &job.encode {
+			Encode::Copy if lossless_args(job).is_none() => {
+				vec![Warning::InexactCopy(job.source.clone())]
+			}
+			_ => Vec::new(),
+		}
+	}
+
+	fn run(&self, job: &Job, progress: &mut Progress) -> Result<()> {
+		let mut cmd = Command::new(&self.path);
+		let job_cmd = self.command(job);
+		// -progress is a global option so it must come before the rest
+		cmd.args(["-nostats", "-progress", "pipe:1"])
+			.args(job_cmd.get_args())
+			.stdout(Stdio::piped());
+
+		// Positions reported by ffmpeg are relative to where the input was seeked to
+		let offset = job.cuts.first().map_or(Duration::ZERO, |c| {
+			Duration::from_micros(c.start * 1_000_000 / FRAMES_PER_SEC)
+		});
+
+		let spawn_err = |source| Error::Spawn {
+			program: self.path.clone(),
+			source,
+		};
+		let mut child = cmd.spawn().map_err(spawn_err)?;
+
+		let stdout = BufReader::new(child.stdout.take().unwrap());
+		for line in stdout.lines() {
+			let line = line.map_err(spawn_err)?;
+			// Despite the name, out_time_ms is also in microseconds
+			let Some(us) = line
+				.strip_prefix("out_time_us=")
+				.and_then(|s| s.trim().parse::<u64>().ok())
+			else {
+				continue;
+			};
+
+			if progress(offset + Duration::from_micros(us)).is_break() {
+				let _ = child.kill();
+				let _ = child.wait();
+				return Err(Error::Cancelled);
+			}
+		}
+
+		let status = child.wait().map_err(spawn_err)?;
+		if status.success() {
+			Ok(())
+		} else {
+			Err(Error::Command {
+				program: self.path.clone(),
+				status,
+			})
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::preset::Preset;
+
+	fn cut(start: u64, end: Option<u64>) -> Cut {
+		Cut {
+			path: format!("{start}.flac").into(),
+			start,
+			end,
+			tags: Vec::new(),
+			number: 1,
+			first: false,
+			last: end.is_none(),
+		}
+	}
+
+	fn args(cuts: Vec<Cut>) -> Vec<String> {
+		let job = Job {
+			cue: "a.cue".into(),
+			source: "a.flac".into(),
+			kind: "WAVE".into(),
+			cuts,
+			encode: Encode::Preset(Preset::Flac),
+			overwrite: Overwrite::Always,
+		};
+		Ffmpeg::default()
+			.command(&job)
+			.get_args()
+			.map(|s| s.to_string_lossy().into_owned())
+			.collect()
+	}
+
+	// The input options, up to the input path.
+	fn input_args(args: &[String]) -> &[String] {
+		let i = args.iter().position(|s| s == "-i").unwrap();
+		&args[..i]
+	}
+
+	fn filter(args: &[String]) -> &str {
+		let i = args.iter().position(|s| s == "-filter_complex").unwrap();
+		&args[i + 1]
+	}
+
+	#[test]
+	fn chunks_only_decode_their_own_tracks() {
+		// A chunk from the middle of a disc: 1 s to 2 s and 2 s to 4 s
+		let args = args(vec![cut(75, Some(150)), cut(150, Some(300))]);
+		assert_eq!(
+			input_args(&args),
+			["-y", "-loglevel", "error", "-ss", "1", "-t", "3"]
+		);
+		assert_eq!(
+			filter(&args),
+			"[0:a:0]asegment=timestamps=1[s0][s1];[s0]asetpts=PTS-STARTPTS[t0];[s1]asetpts=PTS-STARTPTS[t1]"
+		);
+	}
+
+	#[test]
+	fn last_chunk_runs_to_the_end() {
+		let args = args(vec![cut(75, Some(150)), cut(150, None)]);
+		assert_eq!(input_args(&args), ["-y", "-loglevel", "error", "-ss", "1"]);
+		assert!(!filter(&args).contains("anullsink"));
+	}
+
+	#[test]
+	fn gaps_between_tracks_are_discarded() {
+		let args = args(vec![cut(0, Some(75)), cut(150, Some(225))]);
+		assert_eq!(input_args(&args), ["-y", "-loglevel", "error", "-t", "3"]);
+		assert_eq!(
+			filter(&args),
+			"[0:a:0]asegment=timestamps=1|2[s0][s1][s2];[s0]asetpts=PTS-STARTPTS[t0];[s1]anullsink;[s2]asetpts=PTS-STARTPTS[t2]"
+		);
+	}
+}
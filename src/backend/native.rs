@@ -0,0 +1,256 @@
+use std::{
+	fs::{
+		self,
+		File,
+		OpenOptions,
+	},
+	io::{
+		self,
+		BufWriter,
+	},
+	ops::Range,
+	path::Path,
+	time::Duration,
+};
+
+use super::{
+	Backend,
+	Progress,
+};
+use crate::{
+	audio::{
+		self,
+		flac,
+		wav,
+		Block,
+		Format,
+		Source,
+	},
+	plan::{
+		Cut,
+		Encode,
+		Job,
+		Overwrite,
+	},
+	preset::Preset,
+	Error,
+	Result,
+};
+
+/// Splits WAV, AIFF, raw CD audio and FLAC files into WAV or FLAC tracks without any external program.
+///
+/// Cuts are sample-exact. WAV tracks are tagged with a RIFF INFO list, FLAC tracks with Vorbis comments;
+/// pictures embedded in a FLAC source are copied to FLAC tracks.
+#[derive(Debug, Clone, Default)]
+pub struct Native;
+
+impl Native {
+	pub fn new() -> Self {
+		Self
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Container {
+	Wav,
+	Flac,
+}
+
+fn unsupported(msg: String) -> Error {
+	Error::Unsupported {
+		backend: "native",
+		msg,
+	}
+}
+
+fn container(job: &Job) -> Result<Container> {
+	let ext = job.source.extension().and_then(|s| s.to_str());
+	let is_ext = |s: &str| ext.is_some_and(|e| e.eq_ignore_ascii_case(s));
+
+	match &job.encode {
+		Encode::Preset(Preset::Wav) => Ok(Container::Wav),
+		Encode::Preset(Preset::Flac | Preset::FlacComp10) => Ok(Container::Flac),
+		Encode::Copy if is_ext("wav") => Ok(Container::Wav),
+		Encode::Copy if is_ext("flac") => Ok(Container::Flac),
+		_ => Err(unsupported(format!(
+			"can only write WAV and FLAC files, but {} would be converted to another format",
+			job.source.display()
+		))),
+	}
+}
+
+// Opens an output file according to the overwrite policy; returns None if it should be skipped.
+fn create(path: &Path, overwrite: Overwrite) -> Result<Option<File>> {
+	let res = match overwrite {
+		Overwrite::Always => File::create(path),
+		Overwrite::Ask | Overwrite::Never => OpenOptions::new().write(true).create_new(true).open(path),
+	};
+
+	match res {
+		Ok(f) => Ok(Some(f)),
+		Err(e) if e.kind() == io::ErrorKind::AlreadyExists => match overwrite {
+			Overwrite::Never => Ok(None),
+			_ => Err(Error::Exists(path.to_path_buf())),
+		},
+		Err(e) => Err(Error::io(path, e)),
+	}
+}
+
+enum Sink {
+	Wav(wav::Writer<BufWriter<File>>),
+	Flac(Box<flac::Encoder<BufWriter<File>>>),
+}
+
+// A track being written. The file is removed if the track isn't finished, so failed or cancelled jobs don't leave truncated tracks around.
+struct Track<'a> {
+	path: &'a Path,
+	/// `None` if an existing file is being skipped
+	sink: Option<Sink>,
+	/// Position of the first sample after the track
+	end: u64,
+}
+
+impl<'a> Track<'a> {
+	fn create(cut: &'a Cut, job: &Job, src: &Source, container: Container, end: u64) -> Result<Self> {
+		let io_err = |e| Error::io(&cut.path, e);
+		let sink = match create(&cut.path, job.overwrite)? {
+			None => None,
+			Some(f) => {
+				let w = BufWriter::new(f);
+				Some(match container {
+					Container::Wav => Sink::Wav(
+						wav::Writer::new(w, &src.fmt_chunk(), src.format(), &cut.tags)
+							.map_err(io_err)?,
+					),
+					Container::Flac => Sink::Flac(Box::new(
+						flac::Encoder::new(w, src.format(), &cut.tags, src.pictures())
+							.map_err(io_err)?,
+					)),
+				})
+			}
+		};
+
+		Ok(Self {
+			path: &cut.path,
+			sink,
+			end,
+		})
+	}
+
+	fn write(&mut self, block: &Block, range: Range<usize>) -> Result<()> {
+		match &mut self.sink {
+			None => Ok(()),
+			Some(Sink::Wav(w)) => w.write(block, range),
+			Some(Sink::Flac(w)) => w.write(block, range),
+		}
+		.map_err(|e| Error::io(self.path, e))
+	}
+
+	fn finish(mut self) -> Result<()> {
+		let res = match self.sink.take() {
+			None => return Ok(()),
+			Some(Sink::Wav(w)) => w.finish().map(drop),
+			Some(Sink::Flac(w)) => w.finish().map(drop),
+		};
+
+		res.map_err(|e| {
+			let _ = fs::remove_file(self.path);
+			Error::io(self.path, e)
+		})
+	}
+}
+
+impl Drop for Track<'_> {
+	fn drop(&mut self) {
+		if self.sink.take().is_some() {
+			let _ = fs::remove_file(self.path);
+		}
+	}
+}
+
+fn samples_to_duration(format: Format, n: u64) -> Duration {
+	Duration::from_secs_f64(n as f64 / f64::from(format.sample_rate))
+}
+
+impl Backend for Native {
+	fn name(&self) -> &str {
+		"native"
+	}
+
+	fn check(&self, job: &Job) -> Result<()> {
+		if !audio::is_supported(&job.source, &job.kind) {
+			return Err(unsupported(format!(
+				"can only read WAV, AIFF, FLAC and raw CD audio files: {}",
+				job.source.display()
+			)));
+		}
+
+		container(job).map(drop)
+	}
+
+	fn run(&self, job: &Job, progress: &mut Progress) -> Result<()> {
+		let container = container(job)?;
+		let mut src = Source::open(&job.source, &job.kind)?;
+		let format = src.format();
+		let total = src.samples().unwrap_or(u64::MAX);
+
+		let ranges = audio::sample_ranges(&job.cuts, format, total);
+
+		if let Some(first) = ranges.first() {
+			src.seek(first.start)?;
+		}
+
+		let mut block = Block::default();
+		let mut next = 0;
+		let mut current = None::<Track>;
+
+		while (next < job.cuts.len() || current.is_some()) && src.read(&mut block)? {
+			let mut i = 0;
+			while i < block.len {
+				let pos = block.start + i as u64;
+				if current.as_ref().is_some_and(|t| pos >= t.end) {
+					current.take().unwrap().finish()?;
+				}
+
+				let Some(track) = &mut current else {
+					let Some(range) = ranges.get(next) else {
+						break;
+					};
+					if pos < range.start {
+						// Skip audio before the track, such as a hidden pregap
+						i += (range.start - pos).min((block.len - i) as u64) as usize;
+					} else {
+						current = Some(Track::create(
+							&job.cuts[next],
+							job,
+							&src,
+							container,
+							range.end,
+						)?);
+						next += 1;
+					}
+					continue;
+				};
+
+				let n = (track.end - pos).min((block.len - i) as u64) as usize;
+				track.write(&block, i..i + n)?;
+				i += n;
+			}
+
+			let pos = samples_to_duration(format, block.start + block.len as u64);
+			if progress(pos).is_break() {
+				return Err(Error::Cancelled);
+			}
+		}
+
+		if let Some(track) = current {
+			track.finish()?;
+		}
+		// Tracks starting past the end of the source are left empty
+		for (cut, range) in job.cuts.iter().zip(&ranges).skip(next) {
+			Track::create(cut, job, &src, container, range.end)?.finish()?;
+		}
+
+		Ok(())
+	}
+}
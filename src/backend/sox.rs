@@ -0,0 +1,231 @@
+use std::{
+	path::PathBuf,
+	process::Command,
+	sync::OnceLock,
+	time::Duration,
+};
+
+use clap::ValueEnum;
+
+use super::{
+	frames_to_secs,
+	Backend,
+	Progress,
+};
+use crate::{
+	audio,
+	cue::FRAMES_PER_SEC,
+	plan::{
+		Encode,
+		Job,
+		Overwrite,
+	},
+	Error,
+	Result,
+};
+
+/// Splits files by running a SoX executable, once per track.
+///
+/// SoX always decodes and re-encodes, so [`Encode::Copy`] is only supported for lossless formats.
+/// [`Encode::Custom`] arguments are passed as SoX output format options.
+/// Which sources can be read depends on the libraries SoX was built with, so it's asked the first time a job is checked.
+#[derive(Debug, Clone)]
+pub struct Sox {
+	path: PathBuf,
+	// The formats listed by `sox -h`, or `None` if it couldn't be run
+	formats: OnceLock<Option<Vec<String>>>,
+}
+
+impl Default for Sox {
+	fn default() -> Self {
+		Self::new("sox")
+	}
+}
+
+// Formats that can be re-encoded instead of copied without losing anything.
+const LOSSLESS: &[&str] = &["wav", "flac"];
+
+// Raw CD audio has no header, so SoX must be told its format.
+const RAW_LE: &[&str] = &["-t", "raw", "-r", "44100", "-b", "16", "-c", "2", "-e", "signed-integer", "-L"];
+const RAW_BE: &[&str] = &["-t", "raw", "-r", "44100", "-b", "16", "-c", "2", "-e", "signed-integer", "-B"];
+
+fn unsupported(msg: String) -> Error {
+	Error::Unsupported {
+		backend: "sox",
+		msg,
+	}
+}
+
+// The formats in the "AUDIO FILE FORMATS:" line of `sox -h`, e.g. "flac" and "mp3" only if SoX was built with libFLAC and libmad.
+fn parse_formats(help: &str) -> Option<Vec<String>> {
+	help.lines()
+		.find_map(|l| l.strip_prefix("AUDIO FILE FORMATS:"))
+		.map(|l| l.split_whitespace().map(String::from).collect())
+}
+
+fn source_ext(job: &Job) -> &str {
+	job.source
+		.extension()
+		.and_then(|s| s.to_str())
+		.unwrap_or_default()
+}
+
+fn input_args(job: &Job) -> &'static [&'static str] {
+	if job.kind.eq_ignore_ascii_case("BINARY") {
+		RAW_LE
+	} else if job.kind.eq_ignore_ascii_case("MOTOROLA") {
+		RAW_BE
+	} else {
+		&[]
+	}
+}
+
+fn output_args(job: &Job) -> Result<Vec<String>> {
+	let to_vec = |args: &[&str]| args.iter().map(|s| s.to_string()).collect();
+	match &job.encode {
+		Encode::Preset(p) => p.sox_args().map(to_vec).ok_or_else(|| {
+			unsupported(format!(
+				"can't encode with the {} preset",
+				p.to_possible_value().unwrap().get_name()
+			))
+		}),
+		Encode::Copy => {
+			let ext = source_ext(job);
+			LOSSLESS
+				.iter()
+				.find(|s| s.eq_ignore_ascii_case(ext))
+				.map(|s| to_vec(&["-t", s]))
+				.ok_or_else(|| {
+					unsupported(format!(
+						"can't copy the audio in {} without re-encoding it",
+						job.source.display()
+					))
+				})
+		}
+		Encode::Custom(args) => Ok(args.clone()),
+	}
+}
+
+impl Sox {
+	/// `path` is the path to the sox executable.
+	pub fn new(path: impl Into<PathBuf>) -> Self {
+		Self {
+			path: path.into(),
+			formats: OnceLock::new(),
+		}
+	}
+
+	fn formats(&self) -> Option<&[String]> {
+		self.formats
+			.get_or_init(|| {
+				let out = Command::new(&self.path).arg("-h").output().ok()?;
+				parse_formats(&String::from_utf8_lossy(&out.stdout))
+					.or_else(|| parse_formats(&String::from_utf8_lossy(&out.stderr)))
+			})
+			.as_deref()
+	}
+
+	/// Builds the sox commands that run `job`, one per track.
+	pub fn commands(&self, job: &Job) -> Result<Vec<Command>> {
+		let out_args = output_args(job)?;
+
+		let cmds = job
+			.cuts
+			.iter()
+			.map(|cut| {
+				let mut cmd = Command::new(&self.path);
+				cmd.arg("-V1")
+					.args(input_args(job))
+					.arg(&job.source)
+					.args(&out_args);
+
+				// SoX copies the comments of the input unless --comment replaces them
+				for (i, (k, v)) in audio::dedup_tags(&cut.tags).into_iter().enumerate() {
+					cmd.arg(if i == 0 { "--comment" } else { "--add-comment" })
+						.arg(format!("{k}={v}"));
+				}
+
+				cmd.arg(&cut.path)
+					.args(["trim", &frames_to_secs(cut.start)])
+					.args(cut.end.map(|end| format!("={}", frames_to_secs(end))));
+				cmd
+			})
+			.collect();
+
+		Ok(cmds)
+	}
+}
+
+impl Backend for Sox {
+	fn name(&self) -> &str {
+		"sox"
+	}
+
+	fn check(&self, job: &Job) -> Result<()> {
+		// If SoX can't be run, running the job reports that instead
+		let ext = source_ext(job);
+		if let Some(formats) = self.formats().filter(|_| input_args(job).is_empty()) {
+			if !formats.iter().any(|s| s.eq_ignore_ascii_case(ext)) {
+				return Err(unsupported(format!(
+					"can't read {}; it wasn't built with support for .{ext} files",
+					job.source.display()
+				)));
+			}
+		}
+
+		output_args(job).map(drop)
+	}
+
+	/// Progress is reported as each track finishes.
+	fn run(&self, job: &Job, progress: &mut Progress) -> Result<()> {
+		for (cut, mut cmd) in job.cuts.iter().zip(self.commands(job)?) {
+			// SoX overwrites files without asking
+			if cut.path.exists() {
+				match job.overwrite {
+					Overwrite::Always => (),
+					Overwrite::Never => continue,
+					Overwrite::Ask => return Err(Error::Exists(cut.path.clone())),
+				}
+			}
+
+			let status = cmd.status().map_err(|source| Error::Spawn {
+				program: self.path.clone(),
+				source,
+			})?;
+			if !status.success() {
+				return Err(Error::Command {
+					program: self.path.clone(),
+					status,
+				});
+			}
+
+			if let Some(end) = cut.end {
+				let pos = Duration::from_micros(end * 1_000_000 / FRAMES_PER_SEC);
+				if progress(pos).is_break() {
+					return Err(Error::Cancelled);
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn formats_are_read_from_the_help() {
+		let help = "\
+sox:      SoX v14.4.2
+
+AUDIO FILE FORMATS: 8svx aif aifc aiff al au flac raw wav
+PLAYLIST FORMATS: m3u pls
+";
+		let formats = parse_formats(help).unwrap();
+		assert!(formats.iter().any(|f| f == "flac"));
+		assert!(!formats.iter().any(|f| f == "mp3" || f == "m3u"));
+		assert_eq!(parse_formats("sox: SoX v14.4.2\n"), None);
+	}
+}
@@ -0,0 +1,192 @@
+//! User configuration: named templates, custom presets and defaults for command line options.
+//!
+//! The config file is TOML, read from `$XDG_CONFIG_HOME/hermes/config.toml` by default:
+//!
+//! ```toml
+//! out-dir = "~/Music/split"
+//! jobs = 4
+//! overwrite = "never"
+//! template = "library"
+//! preset = "our-opus"
+//!
+//! [templates]
+//! library = "<album-artist?artist>/[<year> - ]<album>/<no>. <title>.<ext>"
+//!
+//! [presets.our-opus]
+//! args = ["-c:a", "libopus", "-b:a", "160k"]
+//! ext = "opus"
+//!
+//! [presets.sox-ogg]
+//! backend = "sox"
+//! args = ["-C", "6"]
+//! ext = "ogg"
+//! ```
+
+use std::{
+	collections::BTreeMap,
+	env,
+	fs,
+	io,
+	path::{
+		Path,
+		PathBuf,
+	},
+};
+
+use serde::Deserialize;
+
+use crate::{
+	backend,
+	plan::{
+		Output,
+		Overwrite,
+	},
+	preset::Preset,
+	template::Template,
+	Error,
+	Result,
+};
+
+/// Encoder arguments and the extension of the files they produce, defined under `[presets.NAME]`.
+///
+/// The arguments are specific to one backend, so the preset can't be used with others.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomPreset {
+	/// Passed to the backend's encoder like arguments after `--` on the command line
+	pub args: Vec<String>,
+	/// The extension without the leading dot, substituted in the template string
+	pub ext: String,
+	/// The backend the arguments are written for: `ffmpeg`, the default, or `sox`
+	#[serde(default)]
+	pub backend: backend::Kind,
+}
+
+/// The contents of a config file; every field is optional.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+	/// Output directory used unless one is given on the command line; `~` expands to the home directory
+	pub out_dir: Option<PathBuf>,
+	/// Maximum number of jobs run in parallel
+	pub jobs: Option<usize>,
+	/// What to do with existing output files: `ask`, `always` or `never`
+	pub overwrite: Option<Overwrite>,
+	/// A template string or the name of one in `templates`, used unless one is given on the command line
+	pub template: Option<String>,
+	/// The name of a built-in or custom preset, used unless one is given on the command line
+	pub preset: Option<String>,
+	/// Templates by name
+	pub templates: BTreeMap<String, String>,
+	/// Custom presets by name; they can't have the name of a built-in preset
+	pub presets: BTreeMap<String, CustomPreset>,
+}
+
+// Built-in presets are looked up by the name used on the command line.
+fn builtin_preset(name: &str) -> Option<Preset> {
+	<Preset as clap::ValueEnum>::from_str(name, false).ok()
+}
+
+fn home_dir() -> Option<PathBuf> {
+	env::var_os("HOME")
+		.or_else(|| env::var_os("USERPROFILE"))
+		.filter(|s| !s.is_empty())
+		.map(PathBuf::from)
+}
+
+/// The default location of the config file.
+///
+/// This is `$XDG_CONFIG_HOME/hermes/config.toml`, falling back to `~/.config/hermes/config.toml`, or `%APPDATA%\hermes\config.toml` on Windows.
+/// Returns `None` if none of these directories are known.
+pub fn default_path() -> Option<PathBuf> {
+	let dir = env::var_os("XDG_CONFIG_HOME")
+		.map(PathBuf::from)
+		.filter(|p| p.is_absolute())
+		.or_else(|| {
+			if cfg!(windows) {
+				env::var_os("APPDATA").map(PathBuf::from)
+			} else {
+				home_dir().map(|p| p.join(".config"))
+			}
+		})?;
+
+	Some(dir.join("hermes").join("config.toml"))
+}
+
+impl Config {
+	/// Reads and checks a config file.
+	///
+	/// Named templates are parsed and custom presets are checked, so mistakes are reported even if they aren't used.
+	pub fn read(path: &Path) -> Result<Self> {
+		let s = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+		let err = |msg: String| Error::Config {
+			path: path.to_path_buf(),
+			msg,
+		};
+
+		let mut config = toml::from_str::<Self>(&s).map_err(|e| err(e.to_string().trim_end().to_string()))?;
+		for (name, t) in &config.templates {
+			Template::parse(t).map_err(|e| err(format!("template {name:?}: {e}")))?;
+		}
+		for (name, p) in &config.presets {
+			if builtin_preset(name).is_some() {
+				return Err(err(format!(
+					"preset {name:?} has the name of a built-in preset"
+				)));
+			} else if p.ext.is_empty() || p.ext.contains(|c: char| !c.is_alphanumeric()) {
+				return Err(err(format!(
+					"preset {name:?}: extensions must be non-empty and consist of alphanumeric characters only"
+				)));
+			} else if p.backend == backend::Kind::Native {
+				return Err(err(format!(
+					"preset {name:?}: the native backend takes no encoder arguments"
+				)));
+			}
+		}
+		if let Some(name) = &config.preset {
+			if config.output(name).is_none() {
+				return Err(err(format!("unknown preset {name:?}")));
+			}
+		}
+
+		if let Some(dir) = config.out_dir.take() {
+			config.out_dir = Some(match (dir.strip_prefix("~"), home_dir()) {
+				(Ok(rest), Some(home)) => home.join(rest),
+				_ => dir,
+			});
+		}
+
+		Ok(config)
+	}
+
+	/// Reads the config file at [`default_path`], or returns an empty config if there is none.
+	pub fn read_default() -> Result<Self> {
+		let Some(path) = default_path() else {
+			return Ok(Self::default());
+		};
+
+		match Self::read(&path) {
+			Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+				Ok(Self::default())
+			}
+			res => res,
+		}
+	}
+
+	/// Resolves `s` to the template of that name, or parses it as a template string if there is none.
+	pub fn template(&self, s: &str) -> Result<Template> {
+		Template::parse(self.templates.get(s).map_or(s, String::as_str))
+	}
+
+	/// Resolves the name of a built-in or custom preset.
+	pub fn output(&self, name: &str) -> Option<Output> {
+		if let Some(p) = builtin_preset(name) {
+			return Some(Output::Preset(p));
+		}
+
+		self.presets.get(name).map(|p| Output::Custom {
+			ext: p.ext.clone(),
+			args: p.args.clone(),
+		})
+	}
+}
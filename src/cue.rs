@@ -1,16 +1,25 @@
 mod error;
 mod parser;
+mod writer;
 
-use std::collections::BTreeMap;
-
-use anyhow::{
-	anyhow,
-	Result,
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{
+		Path,
+		PathBuf,
+	},
 };
 
+use encoding_rs::Encoding;
+
+pub use self::error::Error;
+use crate::Result;
+
 #[derive(Debug, Clone, Default)]
 pub struct Cue {
-	pub rems: BTreeMap<String, String>,
+	/// `REM` comments as key-value pairs, in the order they appear
+	pub rems: Vec<(String, String)>,
 	pub title: Option<String>,
 	pub performer: Option<String>,
 	pub songwriter: Option<String>,
@@ -20,30 +29,130 @@ pub struct Cue {
 
 #[derive(Debug, Clone, Default)]
 pub struct Disc {
-	pub rems: BTreeMap<String, String>,
+	pub rems: Vec<(String, String)>,
 	pub catalog: Option<String>,
 	pub performer: Option<String>,
 	pub songwriter: Option<String>,
 	pub title: Option<String>,
 
 	pub file: String,
+	/// The file type, e.g. `WAVE` or `BINARY`
+	pub kind: String,
 	pub tracks: Vec<Track>,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct Track {
 	pub number: u32,
+	/// The track type, e.g. `AUDIO`
+	pub kind: String,
 	pub title: Option<String>,
 	pub performer: Option<String>,
 	pub songwriter: Option<String>,
 	pub isrc: Option<String>,
+	pub flags: Option<String>,
+	/// Start of the track in milliseconds
 	pub index: u64,
-	pub rems: BTreeMap<String, String>,
+	/// Every `INDEX` declaration of the track, mapping index numbers to CD frames
+	pub indices: BTreeMap<u32, u64>,
+	pub rems: Vec<(String, String)>,
+}
+
+impl Track {
+	/// Start of the track in CD frames, as given by its last `INDEX`.
+	pub fn start(&self) -> u64 {
+		self.indices.values().copied().max().unwrap_or_default()
+	}
 }
 
-pub fn parse(cuesheet: &str) -> Result<Cue> {
+/// Number of CD frames in a second.
+pub const FRAMES_PER_SEC: u64 = 75;
+
+pub const fn frames_to_ms(frames: u64) -> u64 {
+	frames * 1000 / FRAMES_PER_SEC
+}
+
+pub fn parse(cuesheet: &str) -> Result<Cue, Error> {
 	let lines = cuesheet.lines().collect::<Vec<_>>();
-	parser::Parser::new(&lines)
-		.parse()
-		.map_err(|e| anyhow!("line {}: {}\n> {}", e.ln + 1, e.msg, lines[e.ln]))
+	parser::Parser::new(&lines).parse().map_err(|mut e| {
+		e.line = lines.get(e.ln).copied().unwrap_or_default().to_string();
+		e
+	})
+}
+
+/// The result of decoding a cuesheet.
+#[derive(Debug, Clone)]
+pub struct Decoded {
+	pub text: String,
+	pub encoding: &'static Encoding,
+	/// Whether the data started with a byte order mark
+	pub bom: bool,
+	/// Whether malformed sequences were replaced with U+FFFD
+	pub has_replacement: bool,
+}
+
+/// Decodes a cuesheet.
+///
+/// The encoding is, in order of precedence, `encoding`, the one indicated by a UTF-8 or UTF-16 byte order mark or the one guessed by chardetng.
+pub fn decode(data: &[u8], encoding: Option<&'static Encoding>) -> Decoded {
+	let (bom_encoding, bom_len) = Encoding::for_bom(data).unzip();
+	let bom = bom_encoding.is_some();
+	let data = &data[bom_len.unwrap_or(0)..];
+
+	let encoding = encoding.or(bom_encoding).unwrap_or_else(|| {
+		let mut detect = chardetng::EncodingDetector::new();
+		detect.feed(data, true);
+		detect.guess(None, true)
+	});
+
+	let mut dec = encoding.new_decoder_without_bom_handling();
+	let mut buf = String::with_capacity(dec.max_utf8_buffer_length(data.len()).unwrap());
+	buf.extend((0..buf.capacity()).map(|_| '\0'));
+	let (res, _read, len, has_replacement) = dec.decode_to_str(data, &mut buf, true);
+	debug_assert_eq!(res, encoding_rs::CoderResult::InputEmpty);
+	buf.truncate(len);
+
+	Decoded {
+		text: buf,
+		encoding,
+		bom,
+		has_replacement,
+	}
+}
+
+/// A parsed cuesheet along with where it came from.
+#[derive(Debug, Clone)]
+pub struct CueFile {
+	pub path: PathBuf,
+	/// The directory paths in the cuesheet are relative to
+	pub dir: PathBuf,
+	pub cue: Cue,
+	/// The encoding the file was decoded with
+	pub encoding: &'static Encoding,
+	/// Whether the file started with a byte order mark
+	pub bom: bool,
+	/// Whether decoding the file replaced malformed sequences with U+FFFD
+	pub has_replacement: bool,
+}
+
+impl CueFile {
+	/// Reads and parses a cuesheet, detecting its encoding unless `encoding` is set.
+	pub fn read(path: impl Into<PathBuf>, encoding: Option<&'static Encoding>) -> Result<Self> {
+		let path = path.into();
+		let data = fs::read(&path).map_err(|e| crate::Error::io(&path, e))?;
+		let d = decode(&data, encoding);
+		let cue = parse(&d.text).map_err(|source| crate::Error::Cue {
+			path: path.clone(),
+			source,
+		})?;
+
+		Ok(Self {
+			dir: path.parent().map_or_else(PathBuf::new, Path::to_path_buf),
+			path,
+			cue,
+			encoding: d.encoding,
+			bom: d.bom,
+			has_replacement: d.has_replacement,
+		})
+	}
 }
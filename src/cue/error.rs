@@ -1,18 +1,22 @@
-// use core::fmt;
+use core::fmt;
 
+/// An error encountered while parsing a cuesheet.
 #[derive(Debug)]
 pub struct Error {
+	/// The zero-based line number
 	pub ln: usize,
-	pub msg: anyhow::Error,
+	pub msg: String,
+	/// Contents of the offending line
+	pub line: String,
 }
 
-// impl std::error::Error for Error {}
+impl std::error::Error for Error {}
 
-// impl fmt::Display for Error {
-// fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-// write!(f, "line {}: {:?}", self.ln + 1, self.msg)
-// }
-// }
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "line {}: {}\n> {}", self.ln + 1, self.msg, self.line)
+	}
+}
 
 pub trait ErrorCtx<T> {
 	fn line(self, ln: usize) -> Result<T, Error>;
@@ -20,6 +24,10 @@ pub trait ErrorCtx<T> {
 
 impl<T> ErrorCtx<T> for Result<T, anyhow::Error> {
 	fn line(self, ln: usize) -> Result<T, Error> {
-		self.map_err(|msg| Error { ln, msg })
+		self.map_err(|msg| Error {
+			ln,
+			msg: msg.to_string(),
+			line: String::new(),
+		})
 	}
 }
@@ -9,16 +9,19 @@ use super::{
 		Error,
 		ErrorCtx,
 	},
+	frames_to_ms,
 	Cue,
 	Disc,
 	Track,
+	FRAMES_PER_SEC,
 };
 
 macro_rules! err {
 	[$ln:expr, $($args:tt)+] => {
 		Err($crate::cue::error::Error {
 			ln: $ln,
-			msg: anyhow::anyhow!($($args)+),
+			msg: format!($($args)+),
+			line: String::new(),
 		})
 	};
 }
@@ -66,8 +69,12 @@ fn next_word(s: &str) -> Option<(&str, &str)> {
 	Some((&s[end..], &s[..end]))
 }
 
-fn parse_index(input: &str) -> Result<u64> {
-	let (input, _number) = next_word(input).ok_or_else(|| anyhow!("missing index number"))?;
+// Returns the index number and the position in CD frames.
+fn parse_index(input: &str) -> Result<(u32, u64)> {
+	let (input, number) = next_word(input).ok_or_else(|| anyhow!("missing index number"))?;
+	let number = number
+		.parse::<u32>()
+		.map_err(|_| anyhow!("invalid index number: {number}"))?;
 	let input = consume_space1(input)
 		.ok_or_else(|| anyhow!("missing time specifier after index number"))?;
 	let word = parse_val(input)?;
@@ -75,76 +82,40 @@ fn parse_index(input: &str) -> Result<u64> {
 	let nums = word.rsplit(':');
 
 	let mut n = 0;
-	for (field, multiplier) in nums.zip([1, 1000, 60000, 60 * 60000]) {
+	for (field, multiplier) in nums.zip([
+		1,
+		FRAMES_PER_SEC,
+		60 * FRAMES_PER_SEC,
+		60 * 60 * FRAMES_PER_SEC,
+	]) {
 		n += multiplier
 			* field
 				.parse::<u64>()
 				.map_err(|_| anyhow!("invalid index time: {word}"))?;
 	}
 
-	Ok(n)
-}
-
-fn escaped(c: char) -> char {
-	match c {
-		'n' => '\n',
-		't' => '\t',
-		'r' => '\r',
-		_ => c,
-	}
+	Ok((number, n))
 }
 
+// Cuesheets have no escapes, so backslashes are kept as they are, like in `FILE "C:\new\a.wav"`.
 fn parse_str(input: &str) -> Result<(&str, String)> {
 	let input = input.trim_start();
-	let mut buf = String::new();
-	let mut chars = input.char_indices();
-
-	if !input.starts_with('"') {
-		let mut end = 0;
-		while let Some((i, c)) = chars.next() {
-			match c {
-				' ' | '\t' | '\r' | '\n' => break,
-				'\\' => match chars.next() {
-					Some((i, esc)) => {
-						buf.push(escaped(esc));
-						end = i;
-						continue;
-					}
-					None => {
-						buf.push('\\');
-						return Ok(("", buf));
-					}
-				},
-				_ => buf.push(c),
-			}
 
-			end = i;
-		}
-
-		debug_assert!(!buf.is_empty());
+	let Some(quoted) = input.strip_prefix('"') else {
+		let end = input.find([' ', '\t', '\r', '\n']).unwrap_or(input.len());
+		debug_assert!(end > 0);
 
+		let mut buf = input[..end].to_string();
 		trim_string(&mut buf);
-		return Ok((&input[end + 1..], buf));
-	}
-
-	let _ = chars.next().unwrap();
-	while let Some((i, c)) = chars.next() {
-		match c {
-			'"' => {
-				trim_string(&mut buf);
-				return Ok((&input[i + 1..], buf));
-			}
-			'\\' => {
-				let Some((_, esc)) = chars.next() else {
-					break;
-				};
-				buf.push(escaped(esc));
-			}
-			_ => buf.push(c),
-		}
-	}
+		return Ok((&input[end..], buf));
+	};
 
-	Err(anyhow!("unterminated double-quoted string"))
+	let Some(end) = quoted.find('"') else {
+		bail!("unterminated double-quoted string");
+	};
+	let mut buf = quoted[..end].to_string();
+	trim_string(&mut buf);
+	Ok((&quoted[end + 1..], buf))
 }
 
 fn parse_rem(input: &str) -> Result<(String, String)> {
@@ -218,7 +189,7 @@ impl<'a> Parser<'a> {
 			match field.to_lowercase().as_str() {
 				"rem" => {
 					let (k, v) = parse_rem(val).line(ln)?;
-					cue.rems.insert(k, v);
+					cue.rems.push((k, v));
 				}
 				"title" => cue.title = Some(parse_val(val).line(ln)?),
 				"performer" => cue.performer = Some(parse_val(val).line(ln)?),
@@ -250,10 +221,11 @@ impl<'a> Parser<'a> {
 		let (_, field, rest) = self.next().unwrap();
 		debug_assert_eq!("file", &field.to_lowercase());
 
-		let (_kind, file) = parse_str(rest).line(self.ln)?;
+		let (kind, file) = parse_str(rest).line(self.ln)?;
 
 		let mut disc = Disc {
 			file,
+			kind: kind.trim().to_string(),
 			..Disc::default()
 		};
 
@@ -266,7 +238,7 @@ impl<'a> Parser<'a> {
 				}
 				"rem" => {
 					let (k, v) = parse_rem(val).line(ln)?;
-					disc.rems.insert(k, v);
+					disc.rems.push((k, v));
 				}
 				"title" => disc.title = Some(parse_val(val).line(ln)?),
 				"performer" => disc.performer = Some(parse_val(val).line(ln)?),
@@ -288,7 +260,7 @@ impl<'a> Parser<'a> {
 		while let Some((ln, field, val)) = self.next() {
 			debug_assert_eq!("track", &field.to_lowercase());
 
-			let (_kind, no) = parse_str(val).line(ln)?;
+			let (kind, no) = parse_str(val).line(ln)?;
 			let no = no
 				.parse::<u32>()
 				.map_err(|_| anyhow!("invalid track number"))
@@ -296,6 +268,7 @@ impl<'a> Parser<'a> {
 
 			let mut track = Track {
 				number: no,
+				kind: kind.trim().to_string(),
 				..Track::default()
 			};
 
@@ -317,18 +290,19 @@ impl<'a> Parser<'a> {
 						return Ok(disc);
 					}
 					"index" => {
-						let idx = parse_index(val).line(ln)?;
-						track.index = u64::max(track.index, idx);
+						let (no, frames) = parse_index(val).line(ln)?;
+						track.index = u64::max(track.index, frames_to_ms(frames));
+						track.indices.insert(no, frames);
 						have_index = true;
 					}
 					"title" => track.title = Some(parse_val(val).line(ln)?),
 					"performer" => track.performer = Some(parse_val(val).line(ln)?),
 					"songwriter" => track.songwriter = Some(parse_val(val).line(ln)?),
 					"isrc" => track.isrc = Some(parse_val(val).line(ln)?),
-					"flags" => (),
+					"flags" => track.flags = Some(val.trim().to_string()),
 					"rem" => {
 						let (k, v) = parse_rem(val).line(ln)?;
-						track.rems.insert(k, v);
+						track.rems.push((k, v));
 					}
 					_ => return err!(ln, "unknown field for a track: {field}"),
 				}
@@ -344,3 +318,32 @@ impl<'a> Parser<'a> {
 		Ok(disc)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn index_times_are_minutes_seconds_and_frames() {
+		assert_eq!(parse_index("01 00:00:00").unwrap(), (1, 0));
+		// 74 is the last frame of a second; it used to be read as milliseconds
+		assert_eq!(
+			parse_index("01 01:02:74").unwrap(),
+			(1, 74 + 2 * FRAMES_PER_SEC + 60 * FRAMES_PER_SEC)
+		);
+		assert_eq!(parse_index("00 00:01:00").unwrap(), (0, FRAMES_PER_SEC));
+		assert!(parse_index("01 00:xx:00").is_err());
+	}
+
+	#[test]
+	fn track_start_is_converted_to_milliseconds() {
+		let cue = crate::cue::parse(
+			"FILE \"a.wav\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 00 00:01:00\n    INDEX 01 00:02:15\n",
+		)
+		.unwrap();
+		let track = &cue.discs[0].tracks[1];
+		assert_eq!(track.start(), 2 * FRAMES_PER_SEC + 15);
+		assert_eq!(track.index, 2200);
+		assert_eq!(frames_to_ms(FRAMES_PER_SEC), 1000);
+	}
+}
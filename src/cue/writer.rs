@@ -0,0 +1,203 @@
+use core::fmt::{
+	self,
+	Display,
+	Formatter,
+	Write,
+};
+use super::{
+	Cue,
+	Disc,
+	Track,
+	FRAMES_PER_SEC,
+};
+
+// A value that's always written inside double quotes.
+//
+// Cuesheets have no escapes, so backslashes are written as they are and double quotes become single ones.
+struct Quoted<'a>(&'a str);
+
+// A value that's quoted only when it would not parse back otherwise.
+struct Bare<'a>(&'a str);
+
+impl Display for Quoted<'_> {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		f.write_char('"')?;
+		for c in self.0.chars() {
+			match c {
+				'"' => f.write_char('\'')?,
+				// A line break would end the value
+				c if c.is_control() => f.write_char(' ')?,
+				_ => f.write_char(c)?,
+			}
+		}
+		f.write_char('"')
+	}
+}
+
+impl Display for Bare<'_> {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		let needs_quotes = |c: char| c.is_whitespace() || c.is_control() || c == '"';
+		if self.0.is_empty() || self.0.contains(needs_quotes) {
+			Quoted(self.0).fmt(f)
+		} else {
+			f.write_str(self.0)
+		}
+	}
+}
+
+struct Frames(u64);
+
+impl Display for Frames {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		let frames = self.0 % FRAMES_PER_SEC;
+		let secs = self.0 / FRAMES_PER_SEC;
+		write!(f, "{:02}:{:02}:{frames:02}", secs / 60, secs % 60)
+	}
+}
+
+fn write_rems(f: &mut Formatter, indent: &str, rems: &[(String, String)]) -> fmt::Result {
+	for (k, v) in rems {
+		if v.is_empty() {
+			writeln!(f, "{indent}REM {}", Bare(k))?;
+		} else {
+			writeln!(f, "{indent}REM {} {}", Bare(k), Bare(v))?;
+		}
+	}
+
+	Ok(())
+}
+
+fn write_field(f: &mut Formatter, indent: &str, name: &str, val: Option<&str>) -> fmt::Result {
+	match val {
+		Some(val) => writeln!(f, "{indent}{name} {}", Quoted(val)),
+		None => Ok(()),
+	}
+}
+
+impl Display for Cue {
+	/// Serializes the cuesheet with consistent quoting and indentation.
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		write_rems(f, "", &self.rems)?;
+		if let Some(catalog) = &self.catalog {
+			writeln!(f, "CATALOG {}", Bare(catalog))?;
+		}
+		write_field(f, "", "PERFORMER", self.performer.as_deref())?;
+		write_field(f, "", "SONGWRITER", self.songwriter.as_deref())?;
+		write_field(f, "", "TITLE", self.title.as_deref())?;
+
+		for d in &self.discs {
+			d.fmt(f)?;
+		}
+
+		Ok(())
+	}
+}
+
+impl Display for Disc {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		let kind = if self.kind.is_empty() {
+			"WAVE"
+		} else {
+			&self.kind
+		};
+		writeln!(f, "FILE {} {kind}", Quoted(&self.file))?;
+
+		write_rems(f, "  ", &self.rems)?;
+		if let Some(catalog) = &self.catalog {
+			writeln!(f, "  CATALOG {}", Bare(catalog))?;
+		}
+		write_field(f, "  ", "PERFORMER", self.performer.as_deref())?;
+		write_field(f, "  ", "SONGWRITER", self.songwriter.as_deref())?;
+		write_field(f, "  ", "TITLE", self.title.as_deref())?;
+
+		for t in &self.tracks {
+			t.fmt(f)?;
+		}
+
+		Ok(())
+	}
+}
+
+impl Display for Track {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		let kind = if self.kind.is_empty() {
+			"AUDIO"
+		} else {
+			&self.kind
+		};
+		writeln!(f, "  TRACK {:02} {kind}", self.number)?;
+
+		write_field(f, "    ", "TITLE", self.title.as_deref())?;
+		write_field(f, "    ", "PERFORMER", self.performer.as_deref())?;
+		write_field(f, "    ", "SONGWRITER", self.songwriter.as_deref())?;
+		if let Some(isrc) = &self.isrc {
+			writeln!(f, "    ISRC {}", Bare(isrc))?;
+		}
+		if let Some(flags) = self.flags.as_deref().filter(|s| !s.is_empty()) {
+			writeln!(f, "    FLAGS {flags}")?;
+		}
+		write_rems(f, "    ", &self.rems)?;
+
+		for (no, &frames) in &self.indices {
+			writeln!(f, "    INDEX {no:02} {}", Frames(frames))?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn values_are_written_verbatim() {
+		let disc = Disc {
+			file: r"C:\rips\x.wav".into(),
+			title: Some("Say \"hi\"".into()),
+			..Disc::default()
+		};
+		let s = disc.to_string();
+		assert!(s.contains(r#"FILE "C:\rips\x.wav" WAVE"#), "{s}");
+		assert!(s.contains(r#"TITLE "Say 'hi'""#), "{s}");
+
+		assert_eq!(Bare(r"a\b").to_string(), r"a\b");
+		assert_eq!(Bare("a b").to_string(), "\"a b\"");
+		assert_eq!(Quoted("a\nb").to_string(), "\"a b\"");
+	}
+
+	#[test]
+	fn parse_write_parse_round_trip() {
+		let src = r#"REM GENRE Rock
+REM DATE 2001
+REM COMMENT "ripped\twith EAC"
+PERFORMER "AC\DC"
+TITLE "C:\new"
+FILE "C:\rips\new.wav" WAVE
+  REM DISCNUMBER 2
+  TRACK 01 AUDIO
+    TITLE "Back\nslash"
+    REM ZZZ last
+    REM AAA first
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE Bare\word
+    INDEX 00 03:00:00
+    INDEX 01 03:02:10
+"#;
+		let cue = crate::cue::parse(src).unwrap();
+		assert_eq!(cue.title.as_deref(), Some(r"C:\new"));
+		assert_eq!(cue.discs[0].file, r"C:\rips\new.wav");
+		assert_eq!(cue.discs[0].tracks[1].title.as_deref(), Some(r"Bare\word"));
+
+		let written = cue.to_string();
+		let reparsed = crate::cue::parse(&written).unwrap();
+		assert_eq!(format!("{reparsed:?}"), format!("{cue:?}"));
+		assert_eq!(reparsed.to_string(), written);
+
+		// REM lines keep their order
+		let keys = |rems: &[(String, String)]| rems.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+		assert_eq!(keys(&reparsed.rems), ["GENRE", "DATE", "COMMENT"]);
+		assert_eq!(keys(&reparsed.discs[0].tracks[0].rems), ["ZZZ", "AAA"]);
+	}
+}
@@ -0,0 +1,192 @@
+use core::fmt;
+use std::{
+	io,
+	path::PathBuf,
+	process::ExitStatus,
+};
+
+use crate::cue;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+	/// An I/O operation on a path failed
+	Io { path: PathBuf, source: io::Error },
+	/// A cuesheet could not be parsed
+	Cue { path: PathBuf, source: cue::Error },
+	/// A template string is malformed or uses an unknown variable
+	Template(String),
+	/// A config file is malformed or defines something invalid
+	Config { path: PathBuf, msg: String },
+	/// No cuesheets were found in the given path
+	NoCues(PathBuf),
+	/// A cuesheet does not declare any tracks
+	NoTracks(PathBuf),
+	/// A file a cuesheet refers to does not exist
+	MissingFile { cue: PathBuf, file: PathBuf },
+	/// A template variable is used but the cuesheet lacks the information
+	MissingInfo { cue: PathBuf, var: String },
+	/// Two tracks would be written to the same path; `first` and `second` are the cuesheets they come from
+	Collision {
+		path: PathBuf,
+		first: PathBuf,
+		second: PathBuf,
+	},
+	/// An external program could not be started
+	Spawn { program: PathBuf, source: io::Error },
+	/// An external program exited unsuccessfully
+	Command { program: PathBuf, status: ExitStatus },
+	/// A cuesheet could not be repaired
+	Repair { path: PathBuf, msg: String },
+	/// An audio file is malformed or in a format that can't be read
+	Format { path: PathBuf, msg: String },
+	/// A backend can't run a job
+	Unsupported { backend: &'static str, msg: String },
+	/// Checksums of the source can't be written to the tags of a track whose audio differs from it
+	ChecksumTags { path: PathBuf, msg: String },
+	/// An output file exists and the backend can't ask whether to overwrite it
+	Exists(PathBuf),
+	/// A job was cancelled from a progress callback
+	Cancelled,
+	/// The thread pool to run jobs on could not be created
+	ThreadPool(rayon::ThreadPoolBuildError),
+}
+
+impl Error {
+	pub(crate) fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+		Self::Io {
+			path: path.into(),
+			source,
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			// The errors of wrapping variants are left to `source`, so they aren't printed twice in chains
+			Self::Io { path, .. } => write!(f, "{}", path.display()),
+			Self::Cue { path, .. } => write!(f, "error parsing {}", path.display()),
+			Self::Template(msg) => f.write_str(msg),
+			Self::Config { path, msg } => write!(f, "error in {}: {msg}", path.display()),
+			Self::NoCues(path) => write!(f, "no .cue files found in {}", path.display()),
+			Self::NoTracks(path) => write!(f, "cuesheet {} has no tracks", path.display()),
+			Self::MissingFile { cue, file } => write!(
+				f,
+				"file specified in {} does not exist: {}",
+				cue.display(),
+				file.display()
+			),
+			Self::MissingInfo { cue, var } => write!(
+				f,
+				"the <{var}> template variable is used but the cuesheet at {} does not contain {}",
+				cue.display(),
+				match var.as_str() {
+					"year" | "date" => "date information",
+					"album" => "a disc title",
+					_ => "the necessary information",
+				}
+			),
+			Self::Collision {
+				path,
+				first,
+				second,
+			} => {
+				if first == second {
+					write!(
+						f,
+						"multiple tracks in the file {} will have the same file name: {}",
+						first.display(),
+						path.display()
+					)
+				} else {
+					write!(
+						f,
+						"tracks from {} and {} have the same file name: {}",
+						first.display(),
+						second.display(),
+						path.display()
+					)
+				}
+			}
+			Self::Spawn { program, .. } => write!(f, "failed to run {}", program.display()),
+			Self::Command { program, status } => {
+				write!(f, "{} exited with {status}", program.display())
+			}
+			Self::Repair { path, msg } => write!(f, "{}: {msg}", path.display()),
+			Self::Format { path, msg } => write!(f, "{}: {msg}", path.display()),
+			Self::Unsupported { backend, msg } => write!(f, "the {backend} backend {msg}"),
+			Self::ChecksumTags { path, msg } => {
+				write!(f, "can't write checksums to the tags of {}: {msg}", path.display())
+			}
+			Self::Exists(path) => write!(f, "output file already exists: {}", path.display()),
+			Self::Cancelled => f.write_str("the job was cancelled"),
+			Self::ThreadPool(_) => f.write_str("failed to create a thread pool"),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Io { source, .. } | Self::Spawn { source, .. } => Some(source),
+			Self::Cue { source, .. } => Some(source),
+			Self::ThreadPool(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+/// A problem that doesn't stop a job from running, but makes its result differ from what was asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Warning {
+	/// The source's codec can only be copied in whole packets, so copied tracks start and end at the packet boundary nearest to the cue points
+	InexactCopy(PathBuf),
+	/// Checksums can't be computed for a source that isn't CD audio in a format that can be decoded
+	NoChecksums(PathBuf),
+	/// A track from `cue` won't be split because another track is written to `path`
+	SkippedCollision { path: PathBuf, cue: PathBuf },
+}
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::InexactCopy(path) => write!(
+				f,
+				"the audio in {} can only be copied in whole packets; tracks may start or end a few milliseconds off the cue points",
+				path.display()
+			),
+			Self::NoChecksums(path) => write!(
+				f,
+				"checksums can only be computed for CD audio in WAV, AIFF, FLAC or raw files, so tracks from {} won't have any",
+				path.display()
+			),
+			Self::SkippedCollision { path, cue } => write!(
+				f,
+				"skipping a track from {} because another track is written to {}",
+				cue.display(),
+				path.display()
+			),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::error::Error as _;
+
+	use super::*;
+
+	#[test]
+	fn wrapping_variants_expose_their_source() {
+		let e = Error::io("a.flac", io::Error::new(io::ErrorKind::NotFound, "not found"));
+		assert_eq!(e.to_string(), "a.flac");
+		assert_eq!(e.source().unwrap().to_string(), "not found");
+		assert_eq!(format!("{:#}", anyhow::Error::from(e)), "a.flac: not found");
+
+		assert!(Error::NoCues("x".into()).source().is_none());
+	}
+}
@@ -0,0 +1,61 @@
+//! Hermes splits cuesheet + image files into separate tracks.
+//!
+//! The easiest way to split cuesheets is configuring a [`Splitter`] and executing the [`Plan`] it returns.
+//! For more control, cuesheets can be read with [`cue::CueFile::read`], turned into jobs with [`plan::plan`] and split with [`plan::execute`].
+//! Jobs are run by a [`backend::Backend`]; the default one requires an ffmpeg executable.
+
+pub mod accuraterip;
+mod audio;
+pub mod backend;
+pub mod config;
+pub mod cue;
+mod error;
+pub mod manifest;
+pub mod plan;
+pub mod preset;
+pub mod repair;
+pub mod riplog;
+pub mod sanitize;
+mod splitter;
+pub mod template;
+pub mod verify;
+
+use std::path::{
+	Path,
+	PathBuf,
+};
+
+use jwalk::WalkDir;
+
+pub use crate::{
+	error::{
+		Error,
+		Result,
+		Warning,
+	},
+	splitter::{
+		Observer,
+		Plan,
+		Splitter,
+	},
+};
+
+/// Returns the paths of every `.cue` file under `path`, following symbolic links.
+pub fn find_cues(path: &Path) -> Vec<PathBuf> {
+	WalkDir::new(path)
+		.skip_hidden(false)
+		.follow_links(true)
+		.into_iter()
+		.filter_map(|res| match res {
+			Ok(entry)
+				if entry.file_type.is_file()
+					&& Path::new(&entry.file_name)
+						.extension()
+						.is_some_and(|s| s.eq_ignore_ascii_case("cue")) =>
+			{
+				Some(entry.parent_path.join(entry.file_name))
+			}
+			_ => None,
+		})
+		.collect()
+}
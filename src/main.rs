@@ -1,58 +1,67 @@
-mod cue;
-mod preset;
-mod template;
-
 use std::{
-	collections::{
-		btree_map::Entry,
-		BTreeMap,
-	},
-	fs,
 	path::{
 		Path,
 		PathBuf,
 	},
-	process::Command,
-	rc::Rc,
-	sync::Arc,
+	sync::Mutex,
 };
 
 use anyhow::{
 	anyhow,
 	bail,
-	ensure,
 	Result,
 };
 use clap::Parser;
-use jwalk::WalkDir;
-use rayon::prelude::*;
-
-use crate::{
-	cue::{
-		Cue,
-		Disc,
-		Track,
+use encoding_rs::Encoding;
+use hermes::{
+	accuraterip::Checksums,
+	backend,
+	config::Config,
+	cue::CueFile,
+	manifest,
+	plan::{
+		self,
+		Collision,
+		Job,
+		Output,
+		Overwrite,
 	},
 	preset::Preset,
+	repair,
+	riplog,
+	sanitize::{
+		self,
+		Sanitizer,
+	},
 	template::Template,
+	verify::{
+		Outcome,
+		Report,
+	},
+	Error,
+	Observer,
+	Splitter,
+	Warning,
 };
 
-const TEMPLATE_VARS: &[&str] = &["title", "album", "artist", "no", "year", "ext", "dir-name"];
-
 #[derive(Parser)]
 /// Hermes splits cuesheet + image files into separate tracks.
 ///
-/// Requires an ffmpeg executable.
+/// Requires an ffmpeg executable, or sox with --backend sox; WAV and FLAC images can be split without either using --backend native.
 #[command(version)]
 struct Args {
 	/// Path to a cuesheet file or a directory
 	#[arg(group = "action")]
 	path: Option<PathBuf>,
-	/// Maximum number of parallel ffmpeg invocations; defaults to about half the available logical CPU cores
+	/// Maximum number of jobs run in parallel; defaults to the config file, or about half the available logical CPU cores
+	///
+	/// Discs are split into chunks of tracks if there are fewer discs than jobs.
 	#[arg(short, long)]
 	jobs: Option<usize>,
 
 	/// Do not actually split files; useful for checking if there will be errors
+	///
+	/// Also prints the encoding each cuesheet was decoded with.
 	#[arg(long)]
 	dry: bool,
 	/// Overwrite existing output files without asking
@@ -62,22 +71,80 @@ struct Args {
 	#[arg(short, long, conflicts_with = "force")]
 	no_overwrite: bool,
 
-	/// Template string to determine file names
-	#[arg(short, long, value_parser = parse_template, default_value = "<year> - <album>/<no>. <title>.<ext>")]
-	template: Template,
+	/// Template string to determine file names, or the name of a template from the config file
+	///
+	/// Defaults to the config file, or "<year> - <album>/<no>. <title>.<ext>".
+	#[arg(short, long)]
+	template: Option<String>,
+
+	/// Rules for making names from the cuesheet safe to use as file names
+	#[arg(long, default_value = "portable")]
+	sanitize: sanitize::Profile,
+	/// Transliterate names from the cuesheet to ASCII, e.g. "Чайковский" to "Chaikovskii"; tags are left as they are
+	#[arg(long)]
+	transliterate: bool,
+	/// Replace FROM with TO in names from the cuesheet before sanitizing them; can be given several times
+	#[arg(long, value_name = "FROM=TO", value_parser = parse_replacement)]
+	replace: Vec<(String, String)>,
+
+	/// Shorten names of files and directories longer than this many bytes; 0 disables the limit
+	///
+	/// Names are cut between characters and keep their extension. Names that become the same get a number, like "Title (2).flac".
+	#[arg(long, default_value_t = 255)]
+	max_name_bytes: usize,
+	/// Shorten the longest names in output paths longer than this many bytes, including the output directory
+	#[arg(long)]
+	max_path_bytes: Option<usize>,
+
+	/// What to do when several tracks would be written to the same file
+	#[arg(long, default_value = "error")]
+	on_collision: Collision,
 
-	/// Output directory path; defaults to <cue_dir>/split
+	/// Output directory path; defaults to the config file, or <cue_dir>/split
 	#[arg(short, long)]
 	out_dir: Option<PathBuf>,
 
-	/// High-level preset for encoding (defaults to flac)
+	/// High-level preset for encoding, or the name of a preset from the config file; see --list-presets
+	///
+	/// Defaults to the config file, or copying the audio if possible and encoding to FLAC otherwise.
 	#[arg(short, long)]
-	preset: Option<Preset>,
+	preset: Option<String>,
 	/// Do not attempt to avoid re-encoding
+	///
+	/// FLAC and WAV sources are re-encoded to the same format either way, which is lossless and cuts them exactly.
+	/// MP3, AAC, Opus and Vorbis sources are otherwise copied in whole packets, so tracks may start or end a few milliseconds off
+	/// the cue points, and no gapless playback information is written.
 	#[arg(long)]
 	no_copy: bool,
-
-	/// Encoding options to pass to ffmpeg
+	/// Decode the split tracks and check they contain exactly the audio of the source
+	///
+	/// Only WAV, AIFF and FLAC tracks cut from WAV, AIFF, FLAC or raw CD audio can be verified; other tracks are skipped.
+	#[arg(long)]
+	verify: bool,
+	/// Compute AccurateRip v1 and v2 checksums and the CRC32 of every track and print them in a table
+	///
+	/// They're computed from the source audio of each track before splitting, so they're those of a lossless rip,
+	/// whatever the tracks are encoded to. Only CD audio in WAV, AIFF, FLAC or raw files is supported.
+	#[arg(long)]
+	accuraterip: bool,
+	/// Also write the checksums to the ACCURATERIPV1, ACCURATERIPV2 and CRC32 tags of every track
+	///
+	/// Only for tracks copied or encoded to WAV or FLAC; they're decoded after splitting, and an error is reported
+	/// if they don't match the source the checksums were computed from.
+	#[arg(long)]
+	accuraterip_tags: bool,
+	/// Before splitting, check the audio against the CRCs and AccurateRip checksums in EAC or XLD logs next to the cuesheets
+	///
+	/// Splitting is aborted if anything differs. Combine with --dry to only check.
+	#[arg(long)]
+	check_log: bool,
+	/// Write checksum manifests named after each output directory, e.g. Album/Album.md5 (comma-separated)
+	///
+	/// ffp manifests list the MD5 of the decoded audio of FLAC tracks, as shown by `metaflac --show-md5sum`.
+	#[arg(long, value_delimiter = ',')]
+	manifest: Vec<manifest::Kind>,
+
+	/// Encoding options to pass to ffmpeg, or output format options for sox with --backend sox
 	#[arg(
 		short_alias = 'a',
 		alias = "encode-arg",
@@ -93,6 +160,20 @@ struct Args {
 	/// Path to the ffmpeg executable
 	#[arg(long, default_value = "ffmpeg")]
 	ffmpeg: PathBuf,
+	/// Path to the sox executable
+	#[arg(long, default_value = "sox")]
+	sox: PathBuf,
+	/// Read named templates, presets and defaults from this file instead of $XDG_CONFIG_HOME/hermes/config.toml
+	#[arg(long, value_name = "PATH")]
+	config: Option<PathBuf>,
+
+	/// The program used to split files
+	#[arg(long, default_value = "ffmpeg")]
+	backend: backend::Kind,
+
+	/// Decode cuesheets with this encoding instead of detecting it (e.g. shift_jis, windows-1251, utf-16le)
+	#[arg(long, value_parser = parse_encoding)]
+	cue_encoding: Option<&'static Encoding>,
 
 	/// Print help for the template syntax
 	#[arg(long, group = "action")]
@@ -100,32 +181,32 @@ struct Args {
 	/// Show available presets
 	#[arg(long, group = "action")]
 	list_presets: bool,
-}
 
-struct Context<'a> {
-	args: &'a Args,
-
-	force_opt: Option<&'static str>,
-	year: String,
-
-	cue: Cue,
-	dir: Arc<Path>,
+	/// Fix cuesheets in place instead of splitting: re-encode to UTF-8, retarget missing files, renumber tracks and normalize quoting
+	///
+	/// The original file is backed up with a .bak extension. Combine with --dry to only see what would be changed.
+	#[arg(long, requires = "path")]
+	repair: bool,
+	/// Write a UTF-8 byte order mark when repairing cuesheets
+	#[arg(long, requires = "repair")]
+	bom: bool,
 }
 
-struct Job {
-	new_files: Vec<PathBuf>,
-	cmd: Command,
+fn parse_template(config: &Config, s: &str) -> Result<Template> {
+	config
+		.template(s)
+		.map_err(|e| anyhow!("{e}\nrun with --template-help for usage"))
 }
 
-fn parse_template(s: &str) -> Result<Template> {
-	let template = Template::new(s, "<", ">");
-	for s in template.vars() {
-		if !TEMPLATE_VARS.contains(&s) {
-			bail!("unrecognized template variable: <{s}>\nrun with --template-help for usage");
-		}
+fn parse_replacement(s: &str) -> Result<(String, String), &'static str> {
+	match s.split_once('=') {
+		Some((from, to)) if !from.is_empty() => Ok((from.to_string(), to.to_string())),
+		_ => Err("expected FROM=TO, e.g. &=and"),
 	}
+}
 
-	Ok(template)
+fn parse_encoding(s: &str) -> Result<&'static Encoding, String> {
+	Encoding::for_label(s.trim().as_bytes()).ok_or_else(|| format!("unknown encoding: {s}"))
 }
 
 fn validate_ext(s: &str) -> Result<String, &'static str> {
@@ -145,150 +226,256 @@ fn show_template_help() {
 You can use string templates to control generated file names.
 Variables inside angle brackets <> will be replaced with values.
 Allowed variables:
-  - <artist>: Artist name
+  - <artist>: Artist of the track, or of the album
   - <album>: Album name
   - <title>: Song title
   - <no>: The song number, padded with zeroes to the left if necessary
   - <year>: The release year of the album
   - <dir-name>: Name of the directory containing the .cue file
   - <ext>: File extension without any leading dot
-
-Any other variable is an error\
+  - <disc>, <disc-total>: The disc number and number of discs, from REM DISCNUMBER (e.g. \"1/2\") and REM TOTALDISCS
+  - <track-total>: Number of tracks in the cuesheet
+  - <genre>, <date>: From REM GENRE and REM DATE
+  - <composer>: The SONGWRITER of the track, or of the album
+  - <catalog>, <isrc>: The CATALOG of the album and the ISRC of the track
+  - <album-artist>: The PERFORMER of the album
+  - <track-artist>: The PERFORMER of the track, if it differs from the album's
+  - <cue-name>: Name of the .cue file without the extension
+  - <source-ext>: Extension of the file being split
+  - <rem:KEY>: The value of any REM KEY line, e.g. <rem:COMMENT>
+
+Any other variable is an error.
+
+Filters transform the value of a variable and can be chained: <title|lower|truncate:40>
+  - lower, upper: Change the case
+  - ascii: Transliterate to ASCII, e.g. \"Ωmega Café\" becomes \"Omega Cafe\"
+  - truncate:N: Keep at most N characters
+  - pad:N: Pad with zeroes to the left to N characters, e.g. <no|pad:3>
+  - replace:FROM:TO: Replace every occurrence of FROM with TO, e.g. <title|replace:&:and>
+  - default:TEXT: Use TEXT if the variable has no value, e.g. <year|default:Unknown Year>
+
+Values are made safe for file names after their filters, following --sanitize;
+this includes default text, so <album|default:AC/DC> can't add a directory.
+
+Variables separated by ? are tried in order until one has a value: <album?dir-name>
+
+To write a literal <, >, [, ] or \\, put a backslash before it: \\[<year>\\] gives \"[2001]\".
+Other backslashes are kept as they are, but / works as a directory separator everywhere.
+
+Text inside square brackets [] is left out if a variable inside has no value,
+e.g. \"[<year> - ]<album>/<no>. <title>.<ext>\" omits the year and the dash for
+albums without a date. Sections can be nested. Variables outside brackets must
+have a value, except <title> and <artist>, which default to \"(untitled)\" and
+\"(unknown)\".\
 "
 	);
 }
 
-fn normalize(s: &str) -> String {
-	s.chars()
-		.fold(String::with_capacity(s.len()), |mut buf, c| {
-			match c {
-				'"' => buf.push('\''),
-				'<' => buf.push('〈'),
-				'>' => buf.push('﹥'),
-				':' => buf.push(' '),
-				'/' | '\\' => buf.push('-'),
-				'?' => (),
-				'*' => buf.push('﹡'),
-				_ => buf.push(c),
-			}
-
-			buf
-		})
-}
-
-fn try_copy_codec(p: &Path) -> Option<&'static str> {
-	const KNOWN_EXTS: &[&str] = &["wav", "flac", "mp3", "aac", "m4a", "opus", "ogg"];
-	let ext = p.extension()?.to_str()?;
-	KNOWN_EXTS
-		.iter()
-		.copied()
-		.find(|s| s.eq_ignore_ascii_case(ext))
+fn list_presets(config: &Config) {
+	use clap::ValueEnum;
+	for p in Preset::value_variants() {
+		println!(
+			"{}: {}",
+			p.to_possible_value().unwrap().get_name(),
+			p.ffmpeg_args().join(" "),
+		);
+	}
+	for (name, p) in &config.presets {
+		println!("{name}: {} (.{}, {})", p.args.join(" "), p.ext, p.backend.name());
+	}
 }
 
-fn ms_to_ffmpeg(ms: u64) -> String {
-	let sec = ms / 1000;
-	let rem = ms - sec * 1000;
-
-	if rem == 0 {
-		sec.to_string()
-	} else {
-		format!("{sec}.{rem:03}")
+// Adds hints about command line options to library errors.
+fn with_help(e: Error) -> anyhow::Error {
+	match e {
+		Error::Collision { .. } => anyhow!(
+			"{e}\nhelp: specify a different file naming scheme with the --template option, or choose what to do with --on-collision"
+		),
+		Error::Repair { .. } => anyhow!(
+			"{e}\nhelp: specify the correct encoding with --cue-encoding, or run with --force to overwrite existing backups"
+		),
+		Error::ChecksumTags { .. } => anyhow!(
+			"{e}\nhelp: use --preset flac or --preset wav, or print the checksums with --accuraterip instead of tagging them"
+		),
+		Error::Unsupported { .. } => anyhow!(
+			"{e}\nhelp: choose a preset the backend supports with --preset, or another backend with --backend"
+		),
+		Error::MissingInfo { ref var, .. } => anyhow!(
+			"{e}\nhelp: use a fallback like <{var}|default:Unknown> or put the variable in an optional [...] section to keep going"
+		),
+		Error::Exists(_) => anyhow!(
+			"{e}\nhelp: run with --force to overwrite existing files or --no-overwrite to skip them"
+		),
+		e => e.into(),
 	}
 }
 
-fn cue_md(c: &Cue) -> Vec<String> {
-	let mut md = c
-		.rems
-		.iter()
-		.map(|(k, v)| format!("{k}={v}"))
-		.collect::<Vec<_>>();
+// Points out which file a failed job was splitting, since ffmpeg errors don't always say.
+// Also prints the result of verifying tracks and counts them for the summary, and collects checksums.
+#[derive(Default)]
+struct Reporter {
+	/// Tracks that matched, differed and were skipped
+	verified: Mutex<[usize; 3]>,
+	checksums: Mutex<Vec<(PathBuf, u32, Checksums)>>,
+}
 
-	if let Some(artist) = &c.performer {
-		md.push(format!("ARTIST={artist}"));
-		md.push(format!("PERFORMER={artist}"));
-	}
+impl Reporter {
+	fn print_checksums(&self) {
+		let mut rows = self.checksums.lock().unwrap();
+		if rows.is_empty() {
+			return;
+		}
 
-	if let Some(album) = &c.title {
-		md.push(format!("ALBUM={album}"));
+		rows.sort_by(|a, b| a.0.cmp(&b.0));
+		println!("Checksums of the source audio of each track:");
+		println!("Track  AR v1     AR v2     CRC32     File");
+		for (path, number, c) in rows.iter() {
+			println!(
+				"{number:>5}  {:08X}  {:08X}  {:08X}  {}",
+				c.v1,
+				c.v2,
+				c.crc32,
+				path.display()
+			);
+		}
 	}
+}
 
-	if let Some(sw) = &c.songwriter {
-		md.push(format!("SONGWRITER={sw}"));
+impl Observer for Reporter {
+	fn job_checksums(&self, _id: usize, job: &Job, checksums: &[Checksums]) {
+		self.checksums.lock().unwrap().extend(
+			job.cuts
+				.iter()
+				.zip(checksums)
+				.map(|(cut, c)| (cut.path.clone(), cut.number, *c)),
+		);
 	}
 
-	md
-}
-
-fn push_disc_md(d: &Disc, md: &mut Vec<String>) {
-	md.extend(d.rems.iter().map(|(k, v)| format!("{k}={v}")));
+	fn job_verified(&self, _id: usize, _job: &Job, report: &Report) {
+		for t in &report.tracks {
+			match &t.outcome {
+				Outcome::Match => (),
+				Outcome::Mismatch { expected, actual } if expected == actual => {
+					eprintln!("error: {} differs from the source", t.path.display());
+				}
+				Outcome::Mismatch { expected, actual } => eprintln!(
+					"error: {} differs from the source: it has {actual} samples instead of {expected}",
+					t.path.display()
+				),
+				Outcome::Skipped(reason) => {
+					eprintln!("warning: could not verify {}: {reason}", t.path.display());
+				}
+			}
+		}
 
-	if let Some(artist) = &d.performer {
-		md.push(format!("ARTIST={artist}"));
-		md.push(format!("PERFORMER={artist}"));
+		let mut counts = self.verified.lock().unwrap();
+		for t in &report.tracks {
+			counts[match t.outcome {
+				Outcome::Match => 0,
+				Outcome::Mismatch { .. } => 1,
+				Outcome::Skipped(_) => 2,
+			}] += 1;
+		}
 	}
 
-	if let Some(album) = &d.title {
-		md.push(format!("ALBUM={album}"));
+	fn job_failed(&self, _id: usize, job: &Job, _error: &Error) {
+		eprintln!("error: failed to split {}", job.source.display());
 	}
 
-	if let Some(sw) = &d.songwriter {
-		md.push(format!("SONGWRITER={sw}"));
+	fn manifest_written(&self, path: &Path) {
+		println!("wrote {}", path.display());
 	}
 }
 
-fn push_track_md(t: &Track, md: &mut Vec<String>) {
-	md.extend(t.rems.iter().filter_map(|(k, v)| {
-		let v = v.trim();
-		if !k.is_empty() && !v.is_empty() {
-			Some(format!("{k}={v}"))
-		} else {
-			None
-		}
-	}));
+fn read_cue(args: &Args, p: PathBuf) -> Result<CueFile> {
+	let c = CueFile::read(p, args.cue_encoding)?;
 
-	if let Some(title) = &t.title {
-		md.push(format!("TITLE={title}"));
+	if args.dry {
+		println!(
+			"{}: {}{}",
+			c.path.display(),
+			c.encoding.name(),
+			if c.bom { " (with BOM)" } else { "" }
+		);
 	}
-
-	if let Some(artist) = &t.performer {
-		md.push(format!("ARTIST={artist}"));
-		md.push(format!("PERFORMER={artist}"));
+	if c.has_replacement {
+		eprintln!(
+			"warning: {} contains bytes that are invalid in {}; names may be garbled\nhelp: specify the correct encoding with --cue-encoding",
+			c.path.display(),
+			c.encoding.name()
+		);
 	}
 
-	if let Some(sw) = &t.songwriter {
-		md.push(format!("SONGWRITER={sw}"));
+	Ok(c)
+}
+
+fn describe_mismatch(c: &riplog::Comparison) -> String {
+	match c.value {
+		riplog::Value::Length => format!(
+			"{}: {} in the log, {} in the cuesheet and audio",
+			c.value, c.expected, c.actual
+		),
+		_ => format!(
+			"{}: {:08X} in the log, {:08X} computed",
+			c.value, c.expected, c.actual
+		),
 	}
+}
 
-	if let Some(isrc) = &t.isrc {
-		md.push(format!("ISRC={isrc}"));
+// Prints the result of checking every track and returns whether all of them passed.
+fn print_log_checks(reports: &[riplog::Report]) -> bool {
+	for r in reports {
+		println!("{} (checked against {}):", r.cue.display(), r.log.display());
+		for t in &r.tracks {
+			let status = match t.passed() {
+				Some(true) => "ok",
+				Some(false) => "FAILED",
+				None if t.unchecked.is_empty() => "not in the log",
+				None => "not checked",
+			};
+			println!("  track {:>2}: {status} ({})", t.number, t.path.display());
+			for c in t.comparisons.iter().filter(|c| !c.passed()) {
+				println!("    {}", describe_mismatch(c));
+			}
+			for v in &t.unchecked {
+				println!("    {v}: in the log, but couldn't be computed");
+			}
+		}
+		if let Some(c) = &r.range {
+			if c.passed() {
+				println!("  image: ok");
+			} else {
+				println!("  image: FAILED\n    {}", describe_mismatch(c));
+			}
+		}
 	}
 
-	md.push(format!("TRACKNUMBER={}", t.number));
+	reports.iter().all(riplog::Report::passed)
 }
 
-fn parse_cue(p: &Path) -> Result<Cue> {
-	// const BOM: char = '\u{FEFF}';
-	let data = fs::read(p).map_err(|e| anyhow!("error reading {}: {}", p.display(), e))?;
-
-	let mut detect = chardetng::EncodingDetector::new();
-	detect.feed(&data, true);
-	let mut dec = detect.guess(None, true).new_decoder();
-	let mut buf = String::with_capacity(dec.max_utf8_buffer_length(data.len()).unwrap());
-	buf.extend((0..buf.capacity()).map(|_| '\0'));
-	let (res, _read, len, _has_replacement) = dec.decode_to_str(&data, &mut buf, true);
-	debug_assert_eq!(res, encoding_rs::CoderResult::InputEmpty);
-	cue::parse(&buf[..len]).map_err(|e| anyhow!("error parsing {}: {}", p.display(), e))
-}
+fn repair_cues(args: &Args, cues: Vec<PathBuf>) -> Result<()> {
+	let opts = repair::Options {
+		bom: args.bom,
+		dry: args.dry,
+		force: args.force,
+		encoding: args.cue_encoding,
+	};
 
-fn list_presets() {
-	use clap::ValueEnum;
-	for p in Preset::value_variants() {
-		println!(
-			"{}: {}",
-			p.to_possible_value().unwrap().get_name(),
-			p.ffmpeg_args().join(" "),
-		);
+	for p in cues {
+		let dir = p.parent().map_or_else(PathBuf::new, |p| p.to_path_buf());
+		let report = repair::repair_file(&p, &dir, &opts).map_err(with_help)?;
+		for s in &report.warnings {
+			eprintln!("warning: {}: {s}", p.display());
+		}
+		if !report.fixes.is_empty() {
+			println!("{}:", p.display());
+			for s in &report.fixes {
+				println!("  - {s}");
+			}
+		}
 	}
+
+	Ok(())
 }
 
 fn run() -> Result<()> {
@@ -296,290 +483,160 @@ fn run() -> Result<()> {
 	if args.template_help {
 		show_template_help();
 		return Ok(());
-	} else if args.list_presets {
-		list_presets();
+	}
+
+	let config = match &args.config {
+		Some(p) => Config::read(p)?,
+		None => Config::read_default()?,
+	};
+	if args.list_presets {
+		list_presets(&config);
 		return Ok(());
 	}
 
+	let template = parse_template(
+		&config,
+		args.template
+			.as_deref()
+			.or(config.template.as_deref())
+			.unwrap_or(plan::DEFAULT_TEMPLATE),
+	)?;
+	for w in template.warnings() {
+		eprintln!("warning: {w}");
+	}
+
 	let path = args.path.take().unwrap();
 	if !path.exists() {
 		bail!("file or directory does not exist: {}", path.display());
 	}
 
-	if let Some(n) = args.jobs.or_else(|| {
-		std::thread::available_parallelism()
-			.ok()
-			.map(|n| (n.get() / 2 + 1))
-	}) {
-		let _ = rayon::ThreadPoolBuilder::new()
-			.num_threads(n)
-			.build_global();
+	let cues = hermes::find_cues(&path);
+	if cues.is_empty() {
+		return Err(Error::NoCues(path).into());
 	}
 
-	let cues = WalkDir::new(&path)
-		.skip_hidden(false)
-		.follow_links(true)
-		.into_iter()
-		.filter_map(|res| match res {
-			Ok(entry)
-				if entry.file_type.is_file()
-					&& Path::new(&entry.file_name)
-						.extension()
-						.is_some_and(|s| s.eq_ignore_ascii_case("cue")) =>
-			{
-				let p = entry.parent_path.join(entry.file_name);
-				let res = parse_cue(&p).map(move |cue| (cue, entry.parent_path, p));
-
-				Some(res)
-			}
-			_ => None,
-		})
-		.collect::<Result<Vec<_>, _>>()?;
-
-	ensure!(!cues.is_empty(), "no .cue files found");
+	if args.repair {
+		return repair_cues(&args, cues);
+	}
 
-	let force_opt = if args.force {
-		Some("-y")
-	} else if args.no_overwrite {
-		Some("-n")
-	} else {
-		None
+	let cues = cues
+		.into_iter()
+		.map(|p| read_cue(&args, p))
+		.collect::<Result<Vec<_>>>()?;
+
+	// Encode args given on the command line take precedence over a preset from the config file
+	let preset = match &args.preset {
+		Some(name) => Some(name.as_str()),
+		None if !args.encode_arg.is_empty() => None,
+		None => config.preset.as_deref(),
+	};
+	let output = match preset {
+		Some(name) => config.output(name).ok_or_else(|| {
+			anyhow!("unknown preset: {name}\nhelp: run with --list-presets to see the available presets")
+		})?,
+		None if !args.encode_arg.is_empty() => Output::Custom {
+			// At this point the user has specified --ext as well as some encode args
+			ext: args.ext.take().unwrap(),
+			args: std::mem::take(&mut args.encode_arg),
+		},
+		None => Output::default(),
 	};
+	// The arguments of custom presets only make sense to the backend they were written for
+	if let Some((name, p)) = preset.and_then(|name| config.presets.get_key_value(name)) {
+		if p.backend != args.backend {
+			return Err(with_help(Error::Unsupported {
+				backend: args.backend.name(),
+				msg: format!(
+					"can't use preset {name:?}, which is written for the {} backend",
+					p.backend.name()
+				),
+			}));
+		}
+	}
 
-	let need_album = args.template.contains_var("album");
-	let need_year = args.template.contains_var("year");
+	let mut splitter = Splitter::new()
+		.template(template)
+		.output(output)
+		.no_copy(args.no_copy)
+		.on_collision(args.on_collision)
+		.max_name_bytes((args.max_name_bytes > 0).then_some(args.max_name_bytes))
+		.sanitize(Sanitizer {
+			profile: args.sanitize,
+			replacements: std::mem::take(&mut args.replace),
+			transliterate: args.transliterate,
+		})
+		.verify(args.verify)
+		.checksums(args.accuraterip)
+		.checksum_tags(args.accuraterip_tags)
+		.manifests(args.manifest.iter().copied())
+		.overwrite(if args.force {
+			Overwrite::Always
+		} else if args.no_overwrite {
+			Overwrite::Never
+		} else {
+			config.overwrite.unwrap_or_default()
+		})
+		.backend_arc(args.backend.create(&args.ffmpeg, &args.sox));
 
-	let mut jobs = Vec::with_capacity(cues.len());
-	let mut new_files = BTreeMap::new();
+	if let Some(max) = args.max_path_bytes {
+		splitter = splitter.max_path_bytes(max);
+	}
+	if let Some(dir) = args.out_dir.or(config.out_dir) {
+		splitter = splitter.out_dir(dir);
+	}
+	if let Some(n) = args.jobs.or(config.jobs).or_else(|| {
+		std::thread::available_parallelism()
+			.ok()
+			.map(|n| n.get() / 2 + 1)
+	}) {
+		splitter = splitter.jobs(n);
+	}
 
-	for (cue, dir, cue_path) in cues {
-		for disc in &cue.discs {
-			let to_split = dir.join(&disc.file);
-			ensure!(
-				to_split.exists(),
-				"file specified in {} does not exist: {}",
-				cue_path.display(),
-				to_split.display()
-			);
-		}
-		let mut year = String::new();
-		if need_year {
-			year = cue.rems.iter().find_map(|(k, v)| if !v.is_empty() && k.eq_ignore_ascii_case("DATE") {
-				let year = v.split(['-', '.', '/', '\\']).max_by_key(|s| s.len()).filter(|s| !s.is_empty())?;
-				// For validation
-				let _ = year.parse::<u16>().ok()?;
-				Some(normalize(year))
-			} else {
-				None
-			})
-			.ok_or_else(|| anyhow!("<year> template variable is used but the file {} does not contain date information", cue_path.display()))?;
+	let plan = splitter.plan_cues(cues).map_err(with_help)?;
+	for w in plan.warnings() {
+		match w {
+			Warning::InexactCopy(_) => eprintln!(
+				"warning: {w}\nhelp: run with --no-copy to re-encode the tracks and cut them exactly"
+			),
+			_ => eprintln!("warning: {w}"),
 		}
+	}
 
-		if need_album && cue.title.is_none() {
-			bail!("the <album> template variable is used but the cuesheet at {} does not contain a disc title", cue_path.display());
+	if args.check_log {
+		let reports = plan.check_logs()?;
+		if reports.is_empty() {
+			eprintln!("warning: no EAC or XLD logs found next to the cuesheets");
 		}
-
-		let ctx = Context {
-			args: &args,
-			force_opt,
-			year,
-			cue,
-			dir,
-		};
-
-		let mut js = Job::new_jobs(ctx)
-			.map_err(|e| anyhow!("error processing {}: {}", cue_path.display(), e))?;
-
-		let cue_path = Rc::<Path>::from(cue_path);
-
-		for f in js.iter().flat_map(|j| &j.new_files) {
-			match new_files.entry(f.clone()) {
-				Entry::Vacant(x) => _ = x.insert(Rc::clone(&cue_path)),
-				Entry::Occupied(x) => {
-					if *x.get() == cue_path {
-						bail!("multiple tracks in the file {} will have the same file name: {}\nhelp: specify a different file naming scheme with the --template option",
-						cue_path.display(),
-						f.display(),
-						);
-					} else {
-						bail!(
-							"tracks from {} and {} have the same file name\nhelp: specify a different file naming scheme with the --template option",
-							x.get().display(),
-							cue_path.display(),
-						);
-					}
-				}
-			}
+		if !print_log_checks(&reports) {
+			bail!("the audio doesn't match the rip log; it may have been corrupted since ripping");
 		}
-
-		jobs.append(&mut js);
 	}
 
 	if args.dry {
 		return Ok(());
 	}
 
-	jobs.into_par_iter().try_for_each(Job::run)?;
-	Ok(())
-}
-
-impl Job {
-	fn new_jobs(mut c: Context) -> Result<Vec<Self>> {
-		if c.cue
-			.discs
-			.iter()
-			.flat_map(|d| d.tracks.iter())
-			.next()
-			.is_none()
-		{
-			bail!("cuesheet has no tracks");
-		}
+	let reporter = Reporter::default();
+	plan.execute(&reporter).map_err(with_help)?;
+	reporter.print_checksums();
 
-		for d in &mut c.cue.discs {
-			d.tracks.sort_unstable_by_key(|t| t.index);
-		}
-
-		let out_dir = c
-			.args
-			.out_dir
-			.as_ref()
-			.map_or_else(|| c.dir.join("split"), |p| p.clone());
-
-		let mut md = cue_md(&c.cue);
-		let md_trunc = md.len();
-
-		let track_number_width = c
-			.cue
-			.discs
-			.iter()
-			.flat_map(|d| d.tracks.iter().map(|t| t.number))
-			.max()
-			.unwrap_or(1)
-			.ilog10() as usize
-			+ 1;
-
-		let mut jobs = Vec::with_capacity(c.cue.discs.len());
-		// Lazily initialized inside the loop
-		let mut dirname = None;
-
-		for disc in &c.cue.discs {
-			let mut new_files = Vec::with_capacity(disc.tracks.len());
-			let mut cmd = Command::new(&c.args.ffmpeg);
-			cmd.args(c.force_opt).args(["-loglevel", "error"]);
-
-			md.truncate(md_trunc);
-			push_disc_md(disc, &mut md);
-			// Shadow md_trunc for this loop
-			let md_trunc = md.len();
-
-			let to_split = c.dir.join(&disc.file);
-			cmd.arg("-i").arg(&to_split);
-			// Used in template expansion
-			const COPY_ARGS: &[&str] = &["-c", "copy"];
-			let (ext, encode_args) = match c.args.preset {
-				Some(p) => {
-					let ext = p.ext();
-					if !c.args.no_copy
-						&& to_split
-							.extension()
-							.is_some_and(|e| e.eq_ignore_ascii_case(ext))
-					{
-						(ext, COPY_ARGS)
-					} else {
-						(ext, p.ffmpeg_args())
-					}
-				}
-				None if c.args.encode_arg.is_empty() => try_copy_codec(&to_split)
-					.map_or(("flac", Preset::Flac.ffmpeg_args()), |ext| (ext, COPY_ARGS)),
-				None => {
-					// At this point the user has specified --ext as well as some encode args
-					(c.args.ext.as_deref().unwrap(), [].as_slice())
-				}
-			};
-
-			let artist = disc
-				.performer
-				.as_deref()
-				.or(c.cue.performer.as_deref())
-				.map(normalize);
-			let album = disc
-				.title
-				.as_deref()
-				.or(c.cue.title.as_deref())
-				.map(normalize);
-
-			for (i, track) in disc.tracks.iter().enumerate() {
-				let from = ms_to_ffmpeg(track.index);
-				let to = disc.tracks.get(i + 1).map(|t| ms_to_ffmpeg(t.index));
-				let title_in_file = track.title.as_deref().map(normalize);
-
-				let out = c.args.template.expand(|buf, var| match var {
-					"title" => buf.push(title_in_file.as_deref().unwrap_or("(untitled)")),
-					"artist" => buf.push(
-						track
-							.performer
-							.as_deref()
-							.or(artist.as_deref())
-							.unwrap_or("(unknown)"),
-					),
-					"album" => buf.push(album.as_deref().unwrap_or("(unknown)")),
-					"year" => buf.push(&c.year),
-					"no" => buf.push(format!(
-						"{number:0track_number_width$}",
-						number = track.number
-					)),
-					"dir-name" => buf.push(dirname.get_or_insert_with(|| {
-						c.dir
-							.canonicalize()
-							.ok()
-							.and_then(|p| p.file_name().map(|s| s.to_os_string()))
-							.or_else(|| c.dir.file_name().map(|s| s.to_os_string()))
-							.unwrap_or_default()
-					})),
-					"ext" => buf.push(ext),
-					_ => unreachable!(),
-				});
-
-				let out = out_dir.join(out);
-
-				md.truncate(md_trunc);
-				push_track_md(track, &mut md);
-
-				cmd.args(["-ss", &*from])
-					.args(to.as_deref().into_iter().flat_map(|to| ["-to", to]))
-					.args(md.iter().flat_map(|s| ["-metadata", s.as_str()]))
-					.args(encode_args);
-
-				if encode_args.is_empty() {
-					cmd.args(&c.args.encode_arg);
-				}
-				cmd.arg(&out);
-
-				new_files.push(out);
-			}
-
-			jobs.push(Self { cmd, new_files })
-		}
-
-		Ok(jobs)
-	}
-
-	fn run(mut self) -> Result<()> {
-		for parent in self.new_files.iter().filter_map(|p| p.parent()) {
-			fs::create_dir_all(parent)
-				.map_err(|e| anyhow!("error creating directory {}: {}", parent.display(), e))?;
+	if args.verify {
+		let [ok, bad, skipped] = *reporter.verified.lock().unwrap();
+		println!(
+			"verified {} tracks: {ok} identical to the source, {bad} different, {skipped} skipped",
+			ok + bad + skipped
+		);
+		if bad > 0 {
+			bail!("{bad} tracks differ from the source");
 		}
-
-		// println!("{:?}", self.cmd);
-		let status = self.cmd.status()?;
-		ensure!(status.success(), "ffmpeg exited with {status}");
-		Ok(())
 	}
+	Ok(())
 }
 
 fn main() {
 	if let Err(e) = run() {
-		eprintln!("error: {e:?}");
+		// The alternate format joins the chain of sources with colons
+		eprintln!("error: {e:#}");
 		std::process::exit(1);
 	}
 }
@@ -0,0 +1,189 @@
+//! Checksum manifests listing the split tracks of every output directory.
+
+use std::{
+	collections::BTreeMap,
+	fs::{
+		self,
+		File,
+	},
+	io::Read,
+	path::{
+		Path,
+		PathBuf,
+	},
+};
+
+use md5::Md5;
+use rayon::prelude::*;
+use sha2::{
+	Digest,
+	Sha256,
+};
+
+use crate::{
+	audio::{
+		flac,
+		Block,
+		Source,
+	},
+	Error,
+	Result,
+};
+
+/// A manifest format.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Kind {
+	/// MD5 of every file, in the format of `md5sum`
+	Md5,
+	/// SHA-256 of every file, in the format of `sha256sum`
+	Sha256,
+	/// MD5 of the decoded audio of every FLAC file, as stored in its stream info (`.ffp`)
+	Ffp,
+}
+
+impl Kind {
+	/// The extension of manifest files, without the leading dot.
+	pub fn ext(self) -> &'static str {
+		match self {
+			Self::Md5 => "md5",
+			Self::Sha256 => "sha256",
+			Self::Ffp => "ffp",
+		}
+	}
+}
+
+// The hashes of one file; each one is only computed if a manifest needs it.
+#[derive(Default)]
+struct Hashes {
+	md5: Option<String>,
+	sha256: Option<String>,
+	ffp: Option<String>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_flac(p: &Path) -> bool {
+	p.extension().is_some_and(|s| s.eq_ignore_ascii_case("flac"))
+}
+
+// Reads the file once, feeding every hash that's needed.
+fn hash_file(path: &Path, kinds: &[Kind]) -> Result<Hashes> {
+	let mut hashes = Hashes::default();
+	let (want_md5, want_sha256) = (kinds.contains(&Kind::Md5), kinds.contains(&Kind::Sha256));
+	if want_md5 || want_sha256 {
+		let mut f = File::open(path).map_err(|e| Error::io(path, e))?;
+		let mut md5 = Md5::new();
+		let mut sha256 = Sha256::new();
+		let mut buf = vec![0; 1 << 16];
+		loop {
+			let n = f.read(&mut buf).map_err(|e| Error::io(path, e))?;
+			if n == 0 {
+				break;
+			}
+			if want_md5 {
+				md5.update(&buf[..n]);
+			}
+			if want_sha256 {
+				sha256.update(&buf[..n]);
+			}
+		}
+
+		hashes.md5 = want_md5.then(|| hex(&md5.finalize()));
+		hashes.sha256 = want_sha256.then(|| hex(&sha256.finalize()));
+	}
+
+	if kinds.contains(&Kind::Ffp) && is_flac(path) {
+		hashes.ffp = Some(hex(&audio_md5(path)?));
+	}
+
+	Ok(hashes)
+}
+
+// The MD5 from the stream info, or of the decoded audio if the encoder left it unset.
+fn audio_md5(path: &Path) -> Result<[u8; 16]> {
+	let md5 = flac::Reader::open(path)?.md5();
+	if md5 != [0; 16] {
+		return Ok(md5);
+	}
+
+	let mut src = Source::open(path, "")?;
+	let width = usize::from(src.format().bytes_per_sample());
+	let mut hasher = Md5::new();
+	let mut block = Block::default();
+	let mut buf = Vec::new();
+	while src.read(&mut block)? {
+		buf.clear();
+		block.write_le(0..block.len, width, &mut buf);
+		hasher.update(&buf);
+	}
+
+	Ok(hasher.finalize().into())
+}
+
+// Manifests are named after the directory they're in, like `Album/Album.md5`.
+fn manifest_path(dir: &Path, kind: Kind) -> PathBuf {
+	let name = dir
+		.file_name()
+		.map_or_else(|| "checksums".into(), |s| s.to_string_lossy());
+	dir.join(format!("{name}.{}", kind.ext()))
+}
+
+/// Writes a manifest of every kind in `kinds` to each directory containing one of `files`.
+///
+/// Files are hashed in parallel on the current rayon thread pool.
+/// Manifests list the files by name, sorted, and replace existing manifests of the same name.
+/// `.ffp` manifests only list FLAC files and aren't written to directories without any.
+/// Returns the paths of the manifests written.
+pub fn write<'a>(files: impl IntoIterator<Item = &'a Path>, kinds: &[Kind]) -> Result<Vec<PathBuf>> {
+	let mut dirs = BTreeMap::<&Path, Vec<&Path>>::new();
+	for f in files {
+		let dir = f.parent().unwrap_or(Path::new(""));
+		dirs.entry(dir).or_default().push(f);
+	}
+	for files in dirs.values_mut() {
+		files.sort();
+		files.dedup();
+	}
+
+	let hashes = dirs
+		.values()
+		.flatten()
+		.copied()
+		.collect::<Vec<_>>()
+		.into_par_iter()
+		.map(|f| Ok((f, hash_file(f, kinds)?)))
+		.collect::<Result<BTreeMap<_, _>>>()?;
+
+	let mut written = Vec::new();
+	for (dir, files) in &dirs {
+		for &kind in kinds {
+			let mut out = String::new();
+			for f in files {
+				let h = &hashes[f];
+				let name = f.file_name().unwrap_or_default().to_string_lossy();
+				match kind {
+					Kind::Md5 => out += &format!("{}  {name}\n", h.md5.as_deref().unwrap_or_default()),
+					Kind::Sha256 => {
+						out += &format!("{}  {name}\n", h.sha256.as_deref().unwrap_or_default())
+					}
+					Kind::Ffp => {
+						if let Some(md5) = &h.ffp {
+							out += &format!("{name}:{md5}\n");
+						}
+					}
+				}
+			}
+
+			if out.is_empty() {
+				continue;
+			}
+			let path = manifest_path(dir, kind);
+			fs::write(&path, out).map_err(|e| Error::io(&path, e))?;
+			written.push(path);
+		}
+	}
+
+	Ok(written)
+}
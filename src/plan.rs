@@ -0,0 +1,975 @@
+use std::{
+	collections::{
+		btree_map::Entry,
+		BTreeMap,
+	},
+	ffi::OsString,
+	fs,
+	ops::ControlFlow,
+	path::{
+		Component,
+		Path,
+		PathBuf,
+	},
+};
+
+use rayon::prelude::*;
+
+use crate::{
+	backend::Backend,
+	cue::{
+		Cue,
+		CueFile,
+		Disc,
+		Track,
+	},
+	preset::Preset,
+	sanitize::{
+		truncate_name,
+		Sanitizer,
+	},
+	template::Template,
+	Error,
+	Result,
+	Warning,
+};
+
+/// How the split tracks are encoded.
+#[derive(Debug, Clone, Default)]
+pub enum Output {
+	/// Copy the audio stream if the source format is known, otherwise encode to FLAC
+	#[default]
+	Auto,
+	/// Use an encoding preset, copying the stream if the source already has the same format
+	Preset(Preset),
+	/// Pass custom arguments to the backend's encoder
+	Custom {
+		/// The extension without the leading dot, substituted in the template string
+		ext: String,
+		args: Vec<String>,
+	},
+}
+
+/// What to do when an output file already exists.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overwrite {
+	/// Let ffmpeg ask; backends that can't ask fail instead
+	#[default]
+	Ask,
+	Always,
+	Never,
+}
+
+/// What to do when several tracks would be written to the same path.
+///
+/// Paths are compared case-insensitively if the output directory is on a case-insensitive filesystem.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Collision {
+	/// Fail before splitting anything
+	#[default]
+	Error,
+	/// Add a number to the name of every track but the first, like "Interlude (2).flac"
+	Suffix,
+	/// Put the disc number before the names, like "2-Interlude.flac"; from REM DISCNUMBER, or the order of the cuesheets
+	IncludeDisc,
+	/// Only split the first track and warn about the others
+	Skip,
+}
+
+/// Options controlling how cuesheets are turned into jobs.
+#[derive(Debug, Clone)]
+pub struct Options {
+	/// Determines output file names, relative to the output directory
+	pub template: Template,
+	/// Output directory; defaults to `<cue_dir>/split`
+	pub out_dir: Option<PathBuf>,
+	pub output: Output,
+	/// Do not attempt to avoid re-encoding
+	pub no_copy: bool,
+	pub overwrite: Overwrite,
+	/// Makes values from cuesheets safe to use in file names
+	pub sanitize: Sanitizer,
+	/// Longest name of a file or directory in bytes; longer ones are shortened. Defaults to 255, the limit of most filesystems
+	pub max_name_bytes: Option<usize>,
+	/// Longest output path in bytes, including the output directory; the longest names are shortened to fit
+	pub max_path_bytes: Option<usize>,
+	pub on_collision: Collision,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Self {
+			template: Template::parse(DEFAULT_TEMPLATE).unwrap(),
+			out_dir: None,
+			output: Output::Auto,
+			no_copy: false,
+			overwrite: Overwrite::Ask,
+			sanitize: Sanitizer::default(),
+			max_name_bytes: Some(255),
+			max_path_bytes: None,
+			on_collision: Collision::Error,
+		}
+	}
+}
+
+pub const DEFAULT_TEMPLATE: &str = "<year> - <album>/<no>. <title>.<ext>";
+
+struct Context<'a> {
+	opts: &'a Options,
+	year: Option<String>,
+
+	cue: Cue,
+	path: &'a Path,
+	dir: &'a Path,
+}
+
+/// How a job encodes its tracks.
+#[derive(Debug, Clone)]
+pub enum Encode {
+	/// Keep the format of the source
+	///
+	/// Backends may re-encode lossless sources to cut them exactly. Lossy sources are copied in whole packets,
+	/// without trimming the boundary packets or writing gapless metadata; see [`Warning::InexactCopy`].
+	///
+	/// [`Warning::InexactCopy`]: crate::Warning::InexactCopy
+	Copy,
+	Preset(Preset),
+	/// Custom arguments to pass to the backend
+	Custom(Vec<String>),
+}
+
+/// A single output track of a job.
+#[derive(Debug, Clone)]
+pub struct Cut {
+	pub path: PathBuf,
+	/// Start position in CD frames (1/75 of a second)
+	pub start: u64,
+	/// End position in CD frames, or `None` to cut until the end of the source
+	pub end: Option<u64>,
+	/// Metadata to write to the file, as key-value pairs
+	pub tags: Vec<(String, String)>,
+	/// The track number from the cuesheet
+	pub number: u32,
+	/// Whether this is the first track of the cuesheet
+	pub first: bool,
+	/// Whether this is the last track of the cuesheet
+	pub last: bool,
+}
+
+/// Splitting one disc: cutting a source file into tracks, encoding and tagging them.
+///
+/// Jobs are executed by a [`Backend`].
+#[derive(Debug, Clone)]
+pub struct Job {
+	/// The cuesheet the job comes from
+	pub cue: PathBuf,
+	pub source: PathBuf,
+	/// The file type declared in the cuesheet, e.g. `WAVE` or `BINARY`
+	pub kind: String,
+	pub cuts: Vec<Cut>,
+	pub encode: Encode,
+	pub overwrite: Overwrite,
+}
+
+/// Makes a value safe to use in file names with the default [`Sanitizer`].
+pub fn normalize(s: &str) -> String {
+	Sanitizer::default().value(s)
+}
+
+fn try_copy_codec(p: &Path) -> Option<&'static str> {
+	const KNOWN_EXTS: &[&str] = &["wav", "flac", "mp3", "aac", "m4a", "opus", "ogg"];
+	let ext = p.extension()?.to_str()?;
+	KNOWN_EXTS
+		.iter()
+		.copied()
+		.find(|s| s.eq_ignore_ascii_case(ext))
+}
+
+type Tags = Vec<(String, String)>;
+
+// Looks up a REM value case-insensitively, in the first map that has a non-empty one.
+fn rem<'a>(maps: &[&'a [(String, String)]], key: &str) -> Option<&'a str> {
+	maps.iter().find_map(|m| {
+		m.iter()
+			.find(|(k, v)| k.eq_ignore_ascii_case(key) && !v.trim().is_empty())
+			.map(|(_, v)| v.trim())
+	})
+}
+
+fn tag(k: &str, v: &str) -> (String, String) {
+	(k.to_string(), v.to_string())
+}
+
+fn cue_md(c: &Cue) -> Tags {
+	let mut md = c
+		.rems
+		.iter()
+		.map(|(k, v)| (k.clone(), v.clone()))
+		.collect::<Vec<_>>();
+
+	if let Some(artist) = &c.performer {
+		md.push(tag("ARTIST", artist));
+		md.push(tag("PERFORMER", artist));
+	}
+
+	if let Some(album) = &c.title {
+		md.push(tag("ALBUM", album));
+	}
+
+	if let Some(sw) = &c.songwriter {
+		md.push(tag("SONGWRITER", sw));
+	}
+
+	md
+}
+
+fn push_disc_md(d: &Disc, md: &mut Tags) {
+	md.extend(d.rems.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+	if let Some(artist) = &d.performer {
+		md.push(tag("ARTIST", artist));
+		md.push(tag("PERFORMER", artist));
+	}
+
+	if let Some(album) = &d.title {
+		md.push(tag("ALBUM", album));
+	}
+
+	if let Some(sw) = &d.songwriter {
+		md.push(tag("SONGWRITER", sw));
+	}
+}
+
+fn push_track_md(t: &Track, md: &mut Tags) {
+	md.extend(t.rems.iter().filter_map(|(k, v)| {
+		let v = v.trim();
+		if !k.is_empty() && !v.is_empty() {
+			Some(tag(k, v))
+		} else {
+			None
+		}
+	}));
+
+	if let Some(title) = &t.title {
+		md.push(tag("TITLE", title));
+	}
+
+	if let Some(artist) = &t.performer {
+		md.push(tag("ARTIST", artist));
+		md.push(tag("PERFORMER", artist));
+	}
+
+	if let Some(sw) = &t.songwriter {
+		md.push(tag("SONGWRITER", sw));
+	}
+
+	if let Some(isrc) = &t.isrc {
+		md.push(tag("ISRC", isrc));
+	}
+
+	md.push(("TRACKNUMBER".into(), t.number.to_string()));
+}
+
+// Shortens the components of a path relative to `out_dir` to fit the limits in `opts`, appending `suffix` to the file name.
+fn fit_path(out_dir: &Path, mut comps: Vec<OsString>, opts: &Options, suffix: &str) -> PathBuf {
+	let last = comps.len().saturating_sub(1);
+	let fit = |comps: &mut Vec<OsString>, i: usize, max: usize| {
+		if let Some(s) = comps[i].to_str() {
+			comps[i] = truncate_name(s, max, i == last, if i == last { suffix } else { "" }).into();
+		}
+	};
+
+	for i in 0..comps.len() {
+		fit(&mut comps, i, opts.max_name_bytes.unwrap_or(usize::MAX));
+	}
+
+	if let Some(max) = opts.max_path_bytes {
+		let len = |comps: &[OsString]| {
+			out_dir.as_os_str().len() + comps.iter().map(|s| s.len() + 1).sum::<usize>()
+		};
+		// Shorten the longest name until the path fits, or nothing can be shortened anymore
+		while len(&comps) > max {
+			let before = len(&comps);
+			let Some((i, longest)) = comps
+				.iter()
+				.enumerate()
+				.max_by_key(|(_, s)| s.len())
+				.map(|(i, s)| (i, s.len()))
+			else {
+				break;
+			};
+			fit(&mut comps, i, longest.saturating_sub(before - max).max(1));
+			if len(&comps) >= before {
+				break;
+			}
+		}
+	}
+
+	out_dir.join(comps.iter().collect::<PathBuf>())
+}
+
+/// Plans the jobs needed to split every cuesheet, making sure no two tracks are written to the same path.
+///
+/// Tracks that would be are handled as [`Options::on_collision`] says; skipping them is reported with a warning.
+pub fn plan(cues: Vec<CueFile>, opts: &Options) -> Result<(Vec<Job>, Vec<Warning>)> {
+	let need_year = opts.template.contains_var("year");
+
+	let mut jobs = Vec::with_capacity(cues.len());
+	for CueFile { path, dir, cue, .. } in cues {
+		for disc in &cue.discs {
+			let to_split = dir.join(&disc.file);
+			if !to_split.exists() {
+				return Err(Error::MissingFile {
+					cue: path,
+					file: to_split,
+				});
+			}
+		}
+
+		// Missing values are only an error if the template needs them outside optional sections
+		let year = need_year
+			.then(|| {
+				cue.rems.iter().find_map(|(k, v)| {
+					if !v.is_empty() && k.eq_ignore_ascii_case("DATE") {
+						let year = v
+							.split(['-', '.', '/', '\\'])
+							.max_by_key(|s| s.len())
+							.filter(|s| !s.is_empty())?;
+						// For validation
+						let _ = year.parse::<u16>().ok()?;
+						Some(year.to_string())
+					} else {
+						None
+					}
+				})
+			})
+			.flatten();
+
+		let ctx = Context {
+			opts,
+			year,
+			cue,
+			path: &path,
+			dir: &dir,
+		};
+
+		jobs.append(&mut Job::new_jobs(ctx)?);
+	}
+
+	let warnings = resolve_collisions(&mut jobs, opts)?;
+	Ok((jobs, warnings))
+}
+
+// Returns whether names that differ only in case refer to the same file in the nearest existing ancestor of `path`.
+fn is_case_insensitive(path: &Path, cache: &mut BTreeMap<PathBuf, bool>) -> bool {
+	let Some(dir) = path.ancestors().skip(1).find(|p| p.is_dir()) else {
+		return false;
+	};
+	if let Some(&ci) = cache.get(dir) {
+		return ci;
+	}
+
+	// Compare the directory with its name in another case; without letters, look further up
+	let ci = dir
+		.ancestors()
+		.find_map(|p| {
+			let name = p.file_name()?.to_str()?;
+			let flipped = name
+				.chars()
+				.map(|c| match c.is_lowercase() {
+					true => c.to_uppercase().next().unwrap_or(c),
+					false => c.to_lowercase().next().unwrap_or(c),
+				})
+				.collect::<String>();
+			(flipped != name).then(|| same_file(p, &p.with_file_name(flipped)))
+		})
+		.unwrap_or(cfg!(any(windows, target_os = "macos")));
+
+	cache.insert(dir.to_path_buf(), ci);
+	ci
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+	use std::os::unix::fs::MetadataExt;
+	match (fs::metadata(a), fs::metadata(b)) {
+		(Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+		_ => false,
+	}
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Path, b: &Path) -> bool {
+	a.exists() && b.exists()
+}
+
+// The disc number of a track from its DISCNUMBER tag, like "2" in "2/3".
+fn disc_number(cut: &Cut) -> Option<&str> {
+	cut.tags
+		.iter()
+		.rev()
+		.find(|(k, _)| k.eq_ignore_ascii_case("DISCNUMBER"))
+		.map(|(_, v)| v.split('/').next().unwrap_or_default().trim())
+		.filter(|v| !v.is_empty())
+}
+
+// Changes the file name of a track, keeping it within the name length limit.
+fn rename(cut: &mut Cut, prefix: &str, suffix: &str, opts: &Options) {
+	let Some(name) = cut.path.file_name().and_then(|s| s.to_str()) else {
+		return;
+	};
+	let name = format!("{prefix}{name}");
+	let max = opts.max_name_bytes.unwrap_or(usize::MAX);
+	let name = truncate_name(&name, max, true, suffix);
+	cut.path.set_file_name(name);
+}
+
+// Finds tracks that would be written to the same path and handles them as `opts.on_collision` says.
+fn resolve_collisions(jobs: &mut Vec<Job>, opts: &Options) -> Result<Vec<Warning>> {
+	let mut cache = BTreeMap::new();
+	let mut key = |p: &Path| match is_case_insensitive(p, &mut cache) {
+		true => PathBuf::from(p.to_string_lossy().to_lowercase()),
+		false => p.to_path_buf(),
+	};
+
+	// Tracks by path, in the order they were planned
+	let mut paths = BTreeMap::<PathBuf, Vec<(usize, usize)>>::new();
+	for (j, job) in jobs.iter().enumerate() {
+		for (c, cut) in job.cuts.iter().enumerate() {
+			paths.entry(key(&cut.path)).or_default().push((j, c));
+		}
+	}
+	let collisions = paths
+		.values()
+		.filter(|ids| ids.len() > 1)
+		.cloned()
+		.collect::<Vec<_>>();
+	if collisions.is_empty() {
+		return Ok(Vec::new());
+	}
+
+	// Renames a track until its path is free, first without a number
+	let mut place = |jobs: &mut Vec<Job>, (j, c): (usize, usize), prefix: &str| {
+		let original: &mut Cut = &mut jobs[j].cuts[c];
+		for i in 1.. {
+			let mut cut = original.clone();
+			let suffix = if i == 1 {
+				String::new()
+			} else {
+				format!(" ({i})")
+			};
+			rename(&mut cut, prefix, &suffix, opts);
+			if let Entry::Vacant(e) = paths.entry(key(&cut.path)) {
+				e.insert(vec![(j, c)]);
+				*original = cut;
+				break;
+			}
+		}
+	};
+
+	// Cuesheets without a DISCNUMBER are numbered in the order they were given
+	let mut cues = jobs.iter().map(|j| j.cue.clone()).collect::<Vec<_>>();
+	cues.dedup();
+
+	let mut warnings = Vec::new();
+	let mut skipped = Vec::new();
+	for ids in collisions {
+		let (j, c) = ids[0];
+		match opts.on_collision {
+			Collision::Error => {
+				let (j2, _) = ids[1];
+				return Err(Error::Collision {
+					path: jobs[j].cuts[c].path.clone(),
+					first: jobs[j].cue.clone(),
+					second: jobs[j2].cue.clone(),
+				});
+			}
+			Collision::Skip => {
+				for &(j2, c2) in &ids[1..] {
+					warnings.push(Warning::SkippedCollision {
+						path: jobs[j].cuts[c].path.clone(),
+						cue: jobs[j2].cue.clone(),
+					});
+					skipped.push((j2, c2));
+				}
+			}
+			// The first track keeps its name
+			Collision::Suffix => {
+				for &id in &ids[1..] {
+					place(jobs, id, "");
+				}
+			}
+			Collision::IncludeDisc => {
+				for &(j, c) in &ids {
+					let disc = match disc_number(&jobs[j].cuts[c]) {
+						Some(n) => n.to_string(),
+						None => (cues.iter().position(|p| *p == jobs[j].cue).unwrap_or(0) + 1)
+							.to_string(),
+					};
+					// Tracks of the same disc still get a number
+					place(jobs, (j, c), &format!("{disc}-"));
+				}
+			}
+		}
+	}
+
+	// Remove skipped tracks from the back so indices stay valid, then jobs without tracks
+	skipped.sort_unstable();
+	for &(j, c) in skipped.iter().rev() {
+		jobs[j].cuts.remove(c);
+	}
+	jobs.retain(|j| !j.cuts.is_empty());
+
+	Ok(warnings)
+}
+
+/// Splits jobs into chunks of consecutive tracks until there are about `n` jobs, so discs can be split in parallel.
+///
+/// Discs with more tracks are split into more chunks.
+pub fn chunk_jobs(jobs: Vec<Job>, n: usize) -> Vec<Job> {
+	let mut parts = vec![1; jobs.len()];
+	for _ in jobs.len()..n {
+		// The job with the most tracks per chunk that can still be split
+		let Some(i) = (0..jobs.len())
+			.filter(|&i| parts[i] < jobs[i].cuts.len())
+			.max_by(|&a, &b| (jobs[a].cuts.len() * parts[b]).cmp(&(jobs[b].cuts.len() * parts[a])))
+		else {
+			break;
+		};
+		parts[i] += 1;
+	}
+
+	jobs.into_iter()
+		.zip(parts)
+		.flat_map(|(job, n)| job.split(n))
+		.collect()
+}
+
+/// Runs jobs in parallel on the global rayon thread pool.
+pub fn execute(jobs: Vec<Job>, backend: &dyn Backend) -> Result<()> {
+	jobs.into_par_iter().try_for_each(|job| job.run(backend))
+}
+
+impl Job {
+	fn new_jobs(mut c: Context) -> Result<Vec<Self>> {
+		if c.cue
+			.discs
+			.iter()
+			.flat_map(|d| d.tracks.iter())
+			.next()
+			.is_none()
+		{
+			return Err(Error::NoTracks(c.path.to_path_buf()));
+		}
+
+		for d in &mut c.cue.discs {
+			d.tracks.sort_unstable_by_key(|t| t.index);
+		}
+
+		let out_dir = c
+			.opts
+			.out_dir
+			.as_ref()
+			.map_or_else(|| c.dir.join("split"), |p| p.clone());
+
+		let mut md = cue_md(&c.cue);
+		let md_trunc = md.len();
+
+		let track_number_width = c
+			.cue
+			.discs
+			.iter()
+			.flat_map(|d| d.tracks.iter().map(|t| t.number))
+			.max()
+			.unwrap_or(1)
+			.ilog10() as usize
+			+ 1;
+
+		let track_total = c.cue.discs.iter().map(|d| d.tracks.len()).sum::<usize>();
+		let mut jobs = Vec::with_capacity(c.cue.discs.len());
+		// Lazily initialized inside the loop
+		let mut dirname = None;
+		// Output paths and what they were before being shortened
+		let mut shortened = BTreeMap::<PathBuf, PathBuf>::new();
+
+		let n_discs = c.cue.discs.len();
+		for (disc_no, disc) in c.cue.discs.iter().enumerate() {
+			let mut cuts = Vec::with_capacity(disc.tracks.len());
+
+			md.truncate(md_trunc);
+			push_disc_md(disc, &mut md);
+			// Shadow md_trunc for this loop
+			let md_trunc = md.len();
+
+			let to_split = c.dir.join(&disc.file);
+			// ext is used in template expansion
+			let (ext, encode) = match &c.opts.output {
+				&Output::Preset(p) => {
+					let ext = p.ext();
+					if !c.opts.no_copy
+						&& to_split
+							.extension()
+							.is_some_and(|e| e.eq_ignore_ascii_case(ext))
+					{
+						(ext, Encode::Copy)
+					} else {
+						(ext, Encode::Preset(p))
+					}
+				}
+				Output::Auto => try_copy_codec(&to_split)
+					.filter(|_| !c.opts.no_copy)
+					.map_or(("flac", Encode::Preset(Preset::Flac)), |ext| {
+						(ext, Encode::Copy)
+					}),
+				Output::Custom { ext, args } => (ext.as_str(), Encode::Custom(args.clone())),
+			};
+
+			// Values are sanitized by the template, after their filters
+			let artist = disc.performer.as_deref().or(c.cue.performer.as_deref());
+			let source_ext = to_split
+				.extension()
+				.map(|s| s.to_string_lossy().into_owned());
+			let album = disc.title.as_deref().or(c.cue.title.as_deref());
+
+			for (i, track) in disc.tracks.iter().enumerate() {
+				let rems = [&track.rems[..], &disc.rems[..], &c.cue.rems[..]];
+				let raw_rem = |key: &str| rem(&rems, key);
+				let rem = |key: &str| raw_rem(key).map(OsString::from);
+				// "1/2" is a common way to write both the disc number and the total
+				let disc_number =
+					raw_rem("DISCNUMBER").map(|v| v.split_once('/').unwrap_or((v, "")));
+
+				let out = c
+					.opts
+					.template
+					.expand(&c.opts.sanitize, |var| match var {
+						"title" => Some(track.title.as_deref().unwrap_or("(untitled)").into()),
+						"artist" => Some(
+							track
+								.performer
+								.as_deref()
+								.or(artist)
+								.unwrap_or("(unknown)")
+								.into(),
+						),
+						"album" => album.map(Into::into),
+						"year" => c.year.as_deref().map(Into::into),
+						"no" => Some(
+							format!("{number:0track_number_width$}", number = track.number).into(),
+						),
+						"dir-name" => Some(
+							dirname
+								.get_or_insert_with(|| {
+									// Cuesheets in the working directory have an empty parent
+									let dir = if c.dir.as_os_str().is_empty() {
+										Path::new(".")
+									} else {
+										c.dir
+									};
+									dir.canonicalize()
+										.ok()
+										.and_then(|p| p.file_name().map(|s| s.to_os_string()))
+										.or_else(|| dir.file_name().map(|s| s.to_os_string()))
+										.unwrap_or_default()
+								})
+								.clone(),
+						),
+						"ext" => Some(ext.into()),
+						"disc" => disc_number
+							.map(|(n, _)| n.trim())
+							.filter(|n| !n.is_empty())
+							.map(Into::into),
+						"disc-total" => {
+							rem("TOTALDISCS").or_else(|| rem("DISCTOTAL")).or_else(|| {
+								disc_number
+									.map(|(_, total)| total.trim())
+									.filter(|n| !n.is_empty())
+									.map(Into::into)
+							})
+						}
+						"track-total" => Some(track_total.to_string().into()),
+						"genre" => rem("GENRE"),
+						"composer" => track
+							.songwriter
+							.as_deref()
+							.or(disc.songwriter.as_deref())
+							.or(c.cue.songwriter.as_deref())
+							.map(Into::into),
+						"catalog" => disc
+							.catalog
+							.as_deref()
+							.or(c.cue.catalog.as_deref())
+							.map(Into::into),
+						"isrc" => track.isrc.as_deref().map(Into::into),
+						"album-artist" => artist.map(Into::into),
+						// Only set if it differs from the album artist, for templates like "[<track-artist> - ]<title>"
+						"track-artist" => track
+							.performer
+							.as_deref()
+							.filter(|&a| Some(a) != artist)
+							.map(Into::into),
+						"date" => rem("DATE"),
+						"cue-name" => c.path.file_stem().map(Into::into),
+						"source-ext" => source_ext.as_deref().map(Into::into),
+						_ => var.strip_prefix("rem:").and_then(rem),
+					})
+					.map_err(|var| Error::MissingInfo {
+						cue: c.path.to_path_buf(),
+						var: var.to_string(),
+					})?;
+
+				// Leaving out a section can leave a leading separator, which must not make the path absolute
+				let comps = Path::new(&out)
+					.components()
+					.filter_map(|comp| match comp {
+						Component::Prefix(_) | Component::RootDir => None,
+						// Values like ".." become a name too
+						comp => match comp.as_os_str().to_str() {
+							Some(s) => Some(OsString::from(c.opts.sanitize.component(s))),
+							None => Some(comp.as_os_str().to_os_string()),
+						},
+					})
+					.collect::<Vec<_>>();
+
+				// Shortening names can make them equal, which a number tells apart
+				let full = out_dir.join(comps.iter().collect::<PathBuf>());
+				let mut out = fit_path(&out_dir, comps.clone(), c.opts, "");
+				let mut n = 2;
+				while shortened.get(&out).is_some_and(|p| *p != full) {
+					out = fit_path(&out_dir, comps.clone(), c.opts, &format!(" ({n})"));
+					n += 1;
+				}
+				shortened.insert(out.clone(), full);
+
+				md.truncate(md_trunc);
+				push_track_md(track, &mut md);
+
+				cuts.push(Cut {
+					path: out,
+					start: track.start(),
+					end: disc.tracks.get(i + 1).map(|t| t.start()),
+					tags: md.clone(),
+					number: track.number,
+					first: disc_no == 0 && i == 0,
+					last: disc_no + 1 == n_discs && i + 1 == disc.tracks.len(),
+				});
+			}
+
+			jobs.push(Self {
+				cue: c.path.to_path_buf(),
+				source: to_split,
+				kind: disc.kind.clone(),
+				cuts,
+				encode,
+				overwrite: c.opts.overwrite,
+			})
+		}
+
+		Ok(jobs)
+	}
+
+	/// Splits the job into up to `parts` jobs with about the same number of consecutive tracks.
+	pub fn split(mut self, parts: usize) -> Vec<Self> {
+		let len = self.cuts.len();
+		let parts = parts.clamp(1, len.max(1));
+		if parts == 1 {
+			return vec![self];
+		}
+
+		let mut cuts = std::mem::take(&mut self.cuts);
+		let mut jobs = (1..parts)
+			.rev()
+			.map(|i| Self {
+				cuts: cuts.split_off(len * i / parts),
+				..self.clone()
+			})
+			.collect::<Vec<_>>();
+		jobs.push(Self { cuts, ..self });
+		jobs.reverse();
+		jobs
+	}
+
+	/// Paths of the files this job creates.
+	pub fn new_files(&self) -> impl Iterator<Item = &Path> {
+		self.cuts.iter().map(|c| c.path.as_path())
+	}
+
+	/// Creates the output directories of the job.
+	pub fn create_dirs(&self) -> Result<()> {
+		for parent in self.new_files().filter_map(|p| p.parent()) {
+			fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+		}
+
+		Ok(())
+	}
+
+	/// Creates the output directories and runs the job with `backend`, blocking until it's done.
+	pub fn run(&self, backend: &dyn Backend) -> Result<()> {
+		self.create_dirs()?;
+		backend.run(self, &mut |_| ControlFlow::Continue(()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		env,
+		fs,
+		process,
+	};
+
+	use super::*;
+
+	// A job writing a track to each of `paths`, all from the same cuesheet.
+	fn job(paths: &[PathBuf]) -> Job {
+		let cuts = paths
+			.iter()
+			.enumerate()
+			.map(|(i, path)| Cut {
+				path: path.clone(),
+				start: i as u64 * 75,
+				end: Some(i as u64 * 75 + 75),
+				tags: Vec::new(),
+				number: i as u32 + 1,
+				first: i == 0,
+				last: i + 1 == paths.len(),
+			})
+			.collect();
+
+		Job {
+			cue: PathBuf::from("album.cue"),
+			source: PathBuf::from("album.wav"),
+			kind: "WAVE".into(),
+			cuts,
+			encode: Encode::Copy,
+			overwrite: Overwrite::default(),
+		}
+	}
+
+	fn resolve(paths: &[PathBuf], opts: &Options) -> Result<Vec<PathBuf>> {
+		let mut jobs = vec![job(paths)];
+		resolve_collisions(&mut jobs, opts)?;
+		Ok(jobs
+			.iter()
+			.flat_map(|j| &j.cuts)
+			.map(|c| c.path.clone())
+			.collect())
+	}
+
+	fn suffix_opts() -> Options {
+		Options {
+			on_collision: Collision::Suffix,
+			..Options::default()
+		}
+	}
+
+	#[test]
+	fn duplicate_names_get_a_number() {
+		let dir = Path::new("/nonexistent/out");
+		let paths = [
+			"Interlude.flac",
+			"Intro.flac",
+			"Interlude.flac",
+			"Interlude.flac",
+		]
+		.map(|n| dir.join(n));
+		assert_eq!(
+			resolve(&paths, &suffix_opts()).unwrap(),
+			[
+				"Interlude.flac",
+				"Intro.flac",
+				"Interlude (2).flac",
+				"Interlude (3).flac"
+			]
+			.map(|n| dir.join(n))
+		);
+
+		assert!(matches!(
+			resolve(&paths, &Options::default()),
+			Err(Error::Collision { .. })
+		));
+	}
+
+	#[test]
+	fn numbered_names_stay_within_the_limit() {
+		let opts = Options {
+			max_name_bytes: Some(20),
+			..suffix_opts()
+		};
+		let dir = Path::new("/nonexistent/out");
+		// 20 bytes, as long as names may be
+		let paths = ["abcdefghijklmno.flac", "abcdefghijklmno.flac"].map(|n| dir.join(n));
+		let resolved = resolve(&paths, &opts).unwrap();
+		assert_eq!(resolved[1], dir.join("abcdefghijk (2).flac"));
+
+		// Names are cut between characters
+		let paths = ["éééééééx.flac", "éééééééx.flac"].map(|n| dir.join(n));
+		let resolved = resolve(&paths, &opts).unwrap();
+		assert_eq!(resolved[1], dir.join("ééééé (2).flac"));
+		assert!(resolved[1].file_name().unwrap().len() <= 20);
+	}
+
+	// A symlink with the name of a directory in another case makes it look like it's on a case-insensitive filesystem.
+	#[cfg(unix)]
+	#[test]
+	fn names_differing_in_case_collide_on_case_insensitive_filesystems() {
+		let base = env::temp_dir().join(format!("hermes-collisions-{}", process::id()));
+		let insensitive = base.join("Out");
+		let sensitive = base.join("Sensitive");
+		fs::create_dir_all(&insensitive).unwrap();
+		fs::create_dir_all(&sensitive).unwrap();
+		std::os::unix::fs::symlink(&insensitive, base.join("oUT")).unwrap();
+
+		let result = (|| {
+			let paths = [
+				insensitive.join("Title.flac"),
+				insensitive.join("TITLE.flac"),
+			];
+			assert_eq!(
+				resolve(&paths, &suffix_opts())?,
+				[
+					insensitive.join("Title.flac"),
+					insensitive.join("TITLE (2).flac")
+				]
+			);
+			let paths = [sensitive.join("Title.flac"), sensitive.join("TITLE.flac")];
+			assert_eq!(resolve(&paths, &suffix_opts())?, paths);
+			Ok::<_, Error>(())
+		})();
+
+		fs::remove_dir_all(&base).unwrap();
+		result.unwrap();
+	}
+
+	#[test]
+	fn dot_values_stay_inside_the_output_directory() {
+		let dir = env::temp_dir().join(format!("hermes-dots-{}", process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("a.wav"), b"").unwrap();
+		let out = dir.join("out");
+		let opts = Options {
+			template: Template::parse("<artist>/<album>/<title>.<ext>").unwrap(),
+			out_dir: Some(out.clone()),
+			..Options::default()
+		};
+		let cue = crate::cue::parse(
+			"PERFORMER \"..\"\nTITLE \".\"\nFILE \"a.wav\" WAVE\n  TRACK 01 AUDIO\n    TITLE \"Song\"\n    INDEX 01 00:00:00\n",
+		)
+		.unwrap();
+		let cue = CueFile {
+			path: dir.join("a.cue"),
+			dir: dir.clone(),
+			cue,
+			encoding: encoding_rs::UTF_8,
+			bom: false,
+			has_replacement: false,
+		};
+
+		let res = plan(vec![cue], &opts);
+		fs::remove_dir_all(&dir).unwrap();
+		let (jobs, _) = res.unwrap();
+		// ".." becomes a name; "." between other components is left out like in any path
+		assert_eq!(jobs[0].cuts[0].path, out.join("_").join("Song.wav"));
+	}
+}
@@ -71,4 +71,27 @@ impl Preset {
 			LibvorbisUltra => &["-f", "oga", "-c:a", "libvorbis", "-q", "8.0"],
 		}
 	}
+
+	/// Equivalent output options for SoX, if SoX can write the codec.
+	pub const fn sox_args(self) -> Option<&'static [&'static str]> {
+		use Preset::*;
+		Some(match self {
+			Wav => &["-t", "wav"],
+			// SoX tops out at compression level 8
+			Flac | FlacComp10 => &["-t", "flac", "-C", "8"],
+
+			Libmp3lameLow => &["-t", "mp3", "-C", "64"],
+			Libmp3lame => &["-t", "mp3", "-C", "128"],
+			Libmp3lameHigh => &["-t", "mp3", "-C", "224"],
+			Libmp3lameUltra => &["-t", "mp3", "-C", "320"],
+
+			LibvorbisLow => &["-t", "ogg", "-C", "2"],
+			Libvorbis => &["-t", "ogg", "-C", "5"],
+			LibvorbisHigh => &["-t", "ogg", "-C", "6.5"],
+			LibvorbisUltra => &["-t", "ogg", "-C", "8"],
+
+			LibopusLow | Libopus | LibopusHigh | LibopusUltra => return None,
+			LibfdkAacLow | LibfdkAac | LibfdkAacHigh | LibfdkAacUltra => return None,
+		})
+	}
 }
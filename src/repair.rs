@@ -0,0 +1,231 @@
+use std::{
+	fs,
+	path::Path,
+};
+
+use crate::{
+	cue::{
+		self,
+		Cue,
+	},
+	Error,
+	Result,
+};
+
+const AUDIO_EXTS: &[&str] = &[
+	"wav", "flac", "ape", "wv", "tta", "tak", "mp3", "m4a", "aac", "ogg", "opus", "aif", "aiff",
+	"bin", "wma",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+	/// Prepend a byte order mark to the repaired file
+	pub bom: bool,
+	/// Only report what would be changed
+	pub dry: bool,
+	/// Overwrite existing backups
+	pub force: bool,
+	/// Decode cuesheets with this encoding instead of detecting it
+	pub encoding: Option<&'static encoding_rs::Encoding>,
+}
+
+fn is_audio(p: &Path) -> bool {
+	p.extension()
+		.and_then(|s| s.to_str())
+		.is_some_and(|ext| AUDIO_EXTS.iter().any(|s| s.eq_ignore_ascii_case(ext)))
+}
+
+fn file_kind(file: &str) -> &'static str {
+	let ext = Path::new(file)
+		.extension()
+		.and_then(|s| s.to_str())
+		.unwrap_or_default();
+
+	if ext.eq_ignore_ascii_case("mp3") {
+		"MP3"
+	} else if ext.eq_ignore_ascii_case("aif") || ext.eq_ignore_ascii_case("aiff") {
+		"AIFF"
+	} else if ext.eq_ignore_ascii_case("bin") {
+		"BINARY"
+	} else {
+		"WAVE"
+	}
+}
+
+// Finds an existing audio file that a dangling `FILE` declaration most likely refers to.
+fn find_audio(dir: &Path, file: &str, n_discs: usize) -> Result<Option<String>> {
+	let candidates = fs::read_dir(dir)
+		.map_err(|e| Error::io(dir, e))?
+		.filter_map(|entry| {
+			let entry = entry.ok()?;
+			let p = entry.path();
+			if p.is_file() && is_audio(&p) {
+				entry.file_name().into_string().ok()
+			} else {
+				None
+			}
+		})
+		.collect::<Vec<_>>();
+
+	let name = Path::new(file)
+		.file_name()
+		.and_then(|s| s.to_str())
+		.unwrap_or(file);
+	let stem = Path::new(name)
+		.file_stem()
+		.and_then(|s| s.to_str())
+		.unwrap_or(name);
+
+	// Prefer a case-insensitive match, then a file with the same stem and a different extension.
+	let found = candidates
+		.iter()
+		.find(|s| s.to_lowercase() == name.to_lowercase())
+		.or_else(|| {
+			candidates.iter().find(|s| {
+				Path::new(s)
+					.file_stem()
+					.and_then(|s| s.to_str())
+					.is_some_and(|s| s.to_lowercase() == stem.to_lowercase())
+			})
+		})
+		.or_else(|| match &candidates[..] {
+			[only] if n_discs == 1 => Some(only),
+			_ => None,
+		});
+
+	Ok(found.cloned())
+}
+
+/// What was done to a cuesheet.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+	/// A description of each change made
+	pub fixes: Vec<String>,
+	/// Problems that could not be fixed
+	pub warnings: Vec<String>,
+}
+
+/// Applies every fix it can to the cuesheet.
+pub fn repair(cue: &mut Cue, dir: &Path) -> Result<Report> {
+	let mut fixes = Vec::new();
+	let mut warnings = Vec::new();
+	let n_discs = cue.discs.len();
+
+	for disc in &mut cue.discs {
+		if dir.join(&disc.file).exists() {
+			continue;
+		}
+
+		match find_audio(dir, &disc.file, n_discs)? {
+			Some(file) => {
+				fixes.push(format!("FILE {:?} retargeted to {file:?}", disc.file));
+				disc.kind = file_kind(&file).to_string();
+				disc.file = file;
+			}
+			None => warnings.push(format!(
+				"could not find an audio file for FILE {:?} in {}",
+				disc.file,
+				dir.display()
+			)),
+		}
+	}
+
+	let mut sorted = false;
+	let mut renumbered = false;
+	let mut no = 0;
+	for disc in &mut cue.discs {
+		if disc.tracks.windows(2).any(|w| w[0].index > w[1].index) {
+			disc.tracks.sort_by_key(|t| t.index);
+			sorted = true;
+		}
+
+		for t in &mut disc.tracks {
+			no += 1;
+			if t.number != no {
+				t.number = no;
+				renumbered = true;
+			}
+		}
+	}
+
+	if sorted {
+		fixes.push("sorted tracks by their starting position".into());
+	}
+	if renumbered {
+		fixes.push("renumbered tracks".into());
+	}
+
+	Ok(Report { fixes, warnings })
+}
+
+/// Re-encodes a cuesheet to UTF-8 and writes it back after backing up the original.
+///
+/// The file is left untouched and the report has no fixes if it is already in canonical form.
+pub fn repair_file(p: &Path, dir: &Path, opts: &Options) -> Result<Report> {
+	const BOM: &str = "\u{FEFF}";
+
+	let original = fs::read(p).map_err(|e| Error::io(p, e))?;
+	let decoded = cue::decode(&original, opts.encoding);
+	let encoding = decoded.encoding;
+	if decoded.has_replacement {
+		return Err(Error::Repair {
+			path: p.to_path_buf(),
+			msg: format!(
+				"the file contains bytes that are invalid in {}; refusing to re-encode it",
+				encoding.name()
+			),
+		});
+	}
+
+	let mut cue = cue::parse(&decoded.text).map_err(|source| Error::Cue {
+		path: p.to_path_buf(),
+		source,
+	})?;
+	let mut report = repair(&mut cue, dir)?;
+	let fixes = &mut report.fixes;
+
+	let mut data = String::with_capacity(original.len() + BOM.len());
+	if opts.bom {
+		data.push_str(BOM);
+	}
+	data.push_str(&cue.to_string());
+
+	if data.as_bytes() == original {
+		return Ok(report);
+	}
+
+	if encoding != encoding_rs::UTF_8 {
+		fixes.insert(0, format!("re-encoded from {} to UTF-8", encoding.name()));
+	} else if decoded.bom != opts.bom {
+		fixes.insert(
+			0,
+			if opts.bom {
+				"added byte order mark".into()
+			} else {
+				"removed byte order mark".into()
+			},
+		);
+	}
+	if fixes.is_empty() {
+		fixes.push("normalized formatting and quoting".into());
+	}
+
+	if opts.dry {
+		return Ok(report);
+	}
+
+	let mut backup = p.as_os_str().to_os_string();
+	backup.push(".bak");
+	let backup = Path::new(&backup);
+	if !opts.force && backup.exists() {
+		return Err(Error::Repair {
+			path: p.to_path_buf(),
+			msg: format!("backup file {} already exists", backup.display()),
+		});
+	}
+
+	fs::write(backup, &original).map_err(|e| Error::io(backup, e))?;
+	fs::write(p, data).map_err(|e| Error::io(p, e))?;
+
+	Ok(report)
+}
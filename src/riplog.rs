@@ -0,0 +1,533 @@
+//! Reading EAC and XLD rip logs and checking audio against the checksums they record.
+
+use std::{
+	fmt,
+	fs,
+	path::{
+		Path,
+		PathBuf,
+	},
+};
+
+use encoding_rs::UTF_16LE;
+
+use crate::{
+	accuraterip,
+	audio::{
+		self,
+		Block,
+		Source,
+	},
+	cue,
+	plan::Job,
+	Error,
+	Result,
+};
+
+/// The program that wrote a log.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ripper {
+	/// Exact Audio Copy
+	Eac,
+	/// X Lossless Decoder
+	Xld,
+}
+
+/// What a log records about one track.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogTrack {
+	pub number: u32,
+	/// Position of the first sector in the table of contents
+	pub start_sector: Option<u32>,
+	/// Position of the last sector in the table of contents
+	pub end_sector: Option<u32>,
+	/// CRC32 of the audio as it was copied
+	pub copy_crc: Option<u32>,
+	/// AccurateRip v1 checksum
+	pub ar_v1: Option<u32>,
+	/// AccurateRip v2 checksum
+	pub ar_v2: Option<u32>,
+}
+
+/// A parsed rip log.
+#[derive(Debug, Clone)]
+pub struct Log {
+	pub ripper: Ripper,
+	pub tracks: Vec<LogTrack>,
+	/// CRC32 of the whole disc, for logs of rips to a single image
+	pub range_crc: Option<u32>,
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+	let s = s.trim();
+	(s.len() == 8)
+		.then(|| u32::from_str_radix(s, 16).ok())
+		.flatten()
+}
+
+// Parses a row of the table of contents: `number | start | length | start sector | end sector`.
+fn parse_toc_row(line: &str) -> Option<(u32, u32, u32)> {
+	let cols = line.split('|').map(str::trim).collect::<Vec<_>>();
+	match cols[..] {
+		[n, _, _, start, end] => Some((n.parse().ok()?, start.parse().ok()?, end.parse().ok()?)),
+		_ => None,
+	}
+}
+
+// Parses a line starting a track section, like `Track  3` (EAC) or `Track 03` (XLD).
+fn parse_track_header(line: &str) -> Option<u32> {
+	line.strip_prefix("Track")?.trim().parse().ok()
+}
+
+impl Log {
+	fn track(&mut self, number: u32) -> &mut LogTrack {
+		match self.tracks.iter().position(|t| t.number == number) {
+			Some(i) => &mut self.tracks[i],
+			None => {
+				self.tracks.push(LogTrack {
+					number,
+					..LogTrack::default()
+				});
+				self.tracks.last_mut().unwrap()
+			}
+		}
+	}
+
+	/// Parses the text of an EAC or XLD log; only English EAC logs are understood.
+	///
+	/// Returns `None` if the text doesn't look like either.
+	pub fn parse(text: &str) -> Option<Self> {
+		let ripper = if text.contains("Exact Audio Copy") {
+			Ripper::Eac
+		} else if text.contains("X Lossless Decoder") {
+			Ripper::Xld
+		} else {
+			return None;
+		};
+
+		let mut log = Self {
+			ripper,
+			tracks: Vec::new(),
+			range_crc: None,
+		};
+		// The track whose section the current line is in
+		let mut current = None::<u32>;
+		// Whether the line is in the table of contents, and whether a row of it was read
+		let (mut in_toc, mut toc_rows) = (false, false);
+
+		for line in text.lines().map(str::trim) {
+			if line.starts_with("TOC of the extracted CD") {
+				in_toc = true;
+				continue;
+			}
+			if in_toc {
+				if let Some((n, start, end)) = parse_toc_row(line) {
+					let t = log.track(n);
+					t.start_sector = Some(start);
+					t.end_sector = Some(end);
+					toc_rows = true;
+					continue;
+				} else if !toc_rows {
+					// The heading and the line under it
+					continue;
+				}
+				in_toc = false;
+			}
+			if let Some(n) = parse_track_header(line) {
+				current = Some(n);
+				continue;
+			}
+			if line == "All Tracks" || line == "Range status and errors" {
+				current = None;
+				continue;
+			}
+
+			match ripper {
+				Ripper::Eac => log.parse_eac_line(line, current),
+				Ripper::Xld => log.parse_xld_line(line, current),
+			}
+		}
+
+		log.tracks.sort_by_key(|t| t.number);
+		Some(log)
+	}
+
+	fn parse_eac_line(&mut self, line: &str, current: Option<u32>) {
+		if let Some(crc) = line.strip_prefix("Copy CRC").and_then(parse_hex) {
+			match current {
+				Some(n) => self.track(n).copy_crc = Some(crc),
+				None => self.range_crc = Some(crc),
+			}
+			return;
+		}
+
+		// In a track section: `Accurately ripped (confidence 5)  [1A2B3C4D]  (AR v2)`
+		// In the summary: `Track  1  accurately ripped (confidence 5)  [1A2B3C4D]  (AR v2)`
+		let (n, rest) = match line.strip_prefix("Track") {
+			Some(rest) => {
+				let rest = rest.trim_start();
+				let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+				(rest[..digits].parse().ok(), &rest[digits..])
+			}
+			None => (current, line),
+		};
+		let Some(n) = n else {
+			return;
+		};
+		if !rest.trim_start().to_ascii_lowercase().starts_with("accurately ripped") {
+			return;
+		}
+
+		let Some(crc) = rest
+			.split_once('[')
+			.and_then(|(_, s)| s.split_once(']'))
+			.and_then(|(s, _)| parse_hex(s))
+		else {
+			return;
+		};
+		// Logs of EAC versions before AccurateRip v2 don't mention the version
+		if rest.contains("(AR v2)") {
+			self.track(n).ar_v2 = Some(crc);
+		} else {
+			self.track(n).ar_v1 = Some(crc);
+		}
+	}
+
+	fn parse_xld_line(&mut self, line: &str, current: Option<u32>) {
+		let Some((key, value)) = line.split_once(':') else {
+			return;
+		};
+		let Some(value) = parse_hex(value) else {
+			return;
+		};
+
+		match (key.trim(), current) {
+			("CRC32 hash", Some(n)) => self.track(n).copy_crc = Some(value),
+			("CRC32 hash", None) => self.range_crc = Some(value),
+			("AccurateRip v1 signature", Some(n)) => self.track(n).ar_v1 = Some(value),
+			("AccurateRip v2 signature", Some(n)) => self.track(n).ar_v2 = Some(value),
+			_ => (),
+		}
+	}
+
+	/// Reads and parses a log; EAC writes UTF-16 logs, XLD UTF-8 ones.
+	///
+	/// Returns `None` if the file isn't an EAC or XLD log.
+	pub fn read(path: &Path) -> Result<Option<Self>> {
+		let data = fs::read(path).map_err(|e| Error::io(path, e))?;
+		// Byte order marks are detected by cue::decode; UTF-16 without one would be guessed wrong
+		let encoding = (data.len() >= 2 && data[0] != 0 && data[1] == 0).then_some(UTF_16LE);
+		Ok(Self::parse(&cue::decode(&data, encoding).text))
+	}
+}
+
+/// Finds a rip log in `dir`: the first file with a `.log` extension that is an EAC or XLD log, by name.
+pub fn find(dir: &Path) -> Result<Option<(PathBuf, Log)>> {
+	let mut paths = fs::read_dir(dir)
+		.map_err(|e| Error::io(dir, e))?
+		.filter_map(|entry| {
+			let p = entry.ok()?.path();
+			p.extension()
+				.is_some_and(|s| s.eq_ignore_ascii_case("log"))
+				.then_some(p)
+		})
+		.collect::<Vec<_>>();
+	paths.sort();
+
+	for p in paths {
+		if let Some(log) = Log::read(&p)? {
+			return Ok(Some((p, log)));
+		}
+	}
+
+	Ok(None)
+}
+
+/// What a [`Comparison`] compares.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Value {
+	/// The length of a track in sectors, according to the table of contents
+	Length,
+	/// The CRC32 of a track
+	CopyCrc,
+	AccurateRipV1,
+	AccurateRipV2,
+	/// The CRC32 of a whole image
+	ImageCrc,
+}
+
+impl fmt::Display for Value {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Self::Length => "length in sectors",
+			Self::CopyCrc => "Copy CRC",
+			Self::AccurateRipV1 => "AccurateRip v1 checksum",
+			Self::AccurateRipV2 => "AccurateRip v2 checksum",
+			Self::ImageCrc => "Copy CRC of the image",
+		})
+	}
+}
+
+/// A checksum or length from a log compared with the one computed from the audio.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Comparison {
+	pub value: Value,
+	pub expected: u32,
+	pub actual: u32,
+}
+
+impl Comparison {
+	pub fn passed(&self) -> bool {
+		self.expected == self.actual
+	}
+}
+
+/// The result of checking one track against a log.
+#[derive(Debug, Clone)]
+pub struct TrackCheck {
+	pub number: u32,
+	/// The file the track is split into
+	pub path: PathBuf,
+	/// Empty if the log says nothing about the track
+	pub comparisons: Vec<Comparison>,
+	/// Values the log has that couldn't be computed, e.g. checksums of audio that can't be decoded
+	pub unchecked: Vec<Value>,
+}
+
+impl TrackCheck {
+	/// Whether every comparison passed, or `None` if nothing could be compared.
+	///
+	/// Values in [`TrackCheck::unchecked`] don't count as passed or failed.
+	pub fn passed(&self) -> Option<bool> {
+		(!self.comparisons.is_empty()).then(|| self.comparisons.iter().all(Comparison::passed))
+	}
+}
+
+/// The result of checking the tracks of a cuesheet against a log.
+#[derive(Debug, Clone)]
+pub struct Report {
+	pub cue: PathBuf,
+	pub log: PathBuf,
+	pub tracks: Vec<TrackCheck>,
+	/// The CRC32 of the whole image compared with the one in the log, for logs of image rips
+	pub range: Option<Comparison>,
+}
+
+impl Report {
+	/// Whether nothing that could be compared differs.
+	pub fn passed(&self) -> bool {
+		self.range.iter().all(Comparison::passed)
+			&& self.tracks.iter().all(|t| t.passed() != Some(false))
+	}
+}
+
+// The length of a source in CD sectors, without a trailing partial sector, if its header can be read.
+fn source_sectors(job: &Job) -> Result<Option<u64>> {
+	if !audio::is_supported(&job.source, &job.kind) {
+		return Ok(None);
+	}
+
+	let src = Source::open(&job.source, &job.kind)?;
+	let rate = u64::from(src.format().sample_rate);
+	Ok(src.samples().map(|n| n * cue::FRAMES_PER_SEC / rate))
+}
+
+// Compares the tracks of one job with what the log says about them.
+fn check_job(job: &Job, log: &Log) -> Result<Vec<TrackCheck>> {
+	let checksums = accuraterip::compute(job)?;
+	// The last track ends with the source, which is where truncated images are short
+	let source_sectors = match job.cuts.last() {
+		Some(cut) if cut.end.is_none() => source_sectors(job)?,
+		_ => None,
+	};
+
+	let tracks = job
+		.cuts
+		.iter()
+		.enumerate()
+		.map(|(i, cut)| {
+			let mut comparisons = Vec::new();
+			let mut unchecked = Vec::new();
+			let Some(lt) = log.tracks.iter().find(|t| t.number == cut.number) else {
+				return TrackCheck {
+					number: cut.number,
+					path: cut.path.clone(),
+					comparisons,
+					unchecked,
+				};
+			};
+
+			let mut compare = |value, expected: Option<u32>, actual: Option<u32>| match (expected, actual) {
+				(Some(expected), Some(actual)) => comparisons.push(Comparison {
+					value,
+					expected,
+					actual,
+				}),
+				(Some(_), None) => unchecked.push(value),
+				(None, _) => (),
+			};
+
+			// Gaps are appended to the previous track, like the table of contents does
+			let sectors = match cut.end {
+				Some(end) => Some(end - cut.start),
+				None => source_sectors.map(|n| n.saturating_sub(cut.start)),
+			}
+			.map(|n| n as u32);
+			let log_sectors = lt.start_sector.zip(lt.end_sector).map(|(s, e)| e + 1 - s);
+			compare(Value::Length, log_sectors, sectors);
+
+			let c = checksums.as_ref().map(|c| c[i]);
+			compare(Value::CopyCrc, lt.copy_crc, c.map(|c| c.crc32));
+			compare(Value::AccurateRipV1, lt.ar_v1, c.map(|c| c.v1));
+			compare(Value::AccurateRipV2, lt.ar_v2, c.map(|c| c.v2));
+
+			TrackCheck {
+				number: cut.number,
+				path: cut.path.clone(),
+				comparisons,
+				unchecked,
+			}
+		})
+		.collect();
+
+	Ok(tracks)
+}
+
+// Computes the CRC32 of a whole source, if it's CD audio that can be decoded.
+fn source_crc(job: &Job) -> Result<Option<u32>> {
+	let Some(mut src) = accuraterip::open(job)? else {
+		return Ok(None);
+	};
+
+	let mut crc = crc32fast::Hasher::new();
+	let mut block = Block::default();
+	let mut buf = Vec::new();
+	while src.read(&mut block)? {
+		buf.clear();
+		block.write_le(0..block.len, 2, &mut buf);
+		crc.update(&buf);
+	}
+
+	Ok(Some(crc.finalize()))
+}
+
+/// Checks the tracks of the jobs from one cuesheet against a log, decoding the audio they're cut from.
+///
+/// Only CD audio in WAV, AIFF, FLAC or raw files can be decoded; checksums of other tracks aren't compared.
+pub fn check(cue: &Path, log_path: &Path, log: &Log, jobs: &[&Job]) -> Result<Report> {
+	let mut tracks = Vec::new();
+	for job in jobs {
+		tracks.extend(check_job(job, log)?);
+	}
+	tracks.sort_by_key(|t| t.number);
+
+	// The CRC of an image rip covers the whole file
+	let mut range = None;
+	if let (Some(expected), [job, rest @ ..]) = (log.range_crc, jobs) {
+		if rest.iter().all(|j| j.source == job.source) {
+			range = source_crc(job)?.map(|actual| Comparison {
+				value: Value::ImageCrc,
+				expected,
+				actual,
+			});
+		}
+	}
+
+	Ok(Report {
+		cue: cue.to_path_buf(),
+		log: log_path.to_path_buf(),
+		tracks,
+		range,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		env,
+		process,
+	};
+
+	use super::*;
+	use crate::plan::{
+		Cut,
+		Encode,
+		Overwrite,
+	};
+
+	const LOG: &str = "\
+Exact Audio Copy V1.6 from 23. October 2020
+
+TOC of the extracted CD
+
+     Track |   Start  |  Length  | Start sector | End sector
+    ---------------------------------------------------------
+        1  |  0:00.00 |  0:00.04 |         0    |         3
+        2  |  0:00.04 |  0:00.10 |         4    |        13
+
+Range status and errors
+
+     Notes | a | b | 300 | 400
+
+Track  1
+
+     Copy CRC 1A2B3C4D
+
+Track  2
+
+     Copy CRC 5E6F7A8B
+";
+
+	fn job(source: PathBuf, kind: &str) -> Job {
+		let cut = |number, start, end| Cut {
+			path: PathBuf::from(format!("{number}.wav")),
+			start,
+			end,
+			tags: Vec::new(),
+			number,
+			first: number == 1,
+			last: end.is_none(),
+		};
+
+		Job {
+			cue: PathBuf::from("album.cue"),
+			source,
+			kind: kind.into(),
+			cuts: vec![cut(1, 0, Some(4)), cut(2, 4, None)],
+			encode: Encode::Copy,
+			overwrite: Overwrite::default(),
+		}
+	}
+
+	#[test]
+	fn only_rows_of_the_table_of_contents_are_read() {
+		let log = Log::parse(LOG).unwrap();
+		assert_eq!(log.tracks.len(), 2);
+		assert_eq!((log.tracks[1].start_sector, log.tracks[1].end_sector), (Some(4), Some(13)));
+		assert_eq!(log.tracks[1].copy_crc, Some(0x5E6F7A8B));
+	}
+
+	#[test]
+	fn the_last_track_is_checked_against_the_length_of_the_source() {
+		let log = Log::parse(LOG).unwrap();
+		let path = env::temp_dir().join(format!("hermes-riplog-{}.bin", process::id()));
+		let length = |t: &TrackCheck| t.comparisons.iter().find(|c| c.value == Value::Length).copied();
+
+		// A sector and a half short
+		fs::write(&path, vec![0; (12 * 588 + 294) * 4]).unwrap();
+		let res = check_job(&job(path.clone(), "BINARY"), &log);
+		fs::remove_file(&path).unwrap();
+		let tracks = res.unwrap();
+		assert_eq!(tracks[0].passed(), Some(false), "the Copy CRCs differ");
+		assert!(length(&tracks[0]).unwrap().passed());
+		let last = length(&tracks[1]).unwrap();
+		assert_eq!((last.expected, last.actual), (10, 8));
+
+		// Values that can't be computed are reported, not passed
+		let tracks = check_job(&job(PathBuf::from("/nonexistent/album.mp3"), "MP3"), &log).unwrap();
+		assert!(length(&tracks[0]).unwrap().passed());
+		assert_eq!(tracks[0].unchecked, [Value::CopyCrc]);
+		assert_eq!(tracks[1].passed(), None);
+		assert_eq!(tracks[1].unchecked, [Value::Length, Value::CopyCrc]);
+	}
+}
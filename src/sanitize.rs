@@ -0,0 +1,193 @@
+//! Making cuesheet values safe to use in file names on different filesystems.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A set of file name rules.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Profile {
+	/// Replace characters that are invalid on common filesystems with lookalikes, e.g. `*` with `﹡`
+	#[default]
+	Portable,
+	/// Only replace `/` and remove control characters
+	Posix,
+	/// Windows and SMB shares: also avoid reserved names like `CON` and trailing dots and spaces
+	#[value(alias = "smb")]
+	Windows,
+	/// FAT32 and exFAT drives: Windows rules, but replace invalid characters with ASCII
+	#[value(alias = "exfat")]
+	Fat32,
+	/// FAT32 rules, and transliterate everything else to printable ASCII
+	Ascii,
+}
+
+// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+	"CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+	"COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+impl Profile {
+	// Whether names must follow the rules of Windows beyond invalid characters.
+	fn windows_names(self) -> bool {
+		matches!(self, Self::Windows | Self::Fat32 | Self::Ascii)
+	}
+
+	// The replacement of a character, which may be empty; `None` keeps it.
+	// Names that must be ASCII get ASCII replacements whatever the profile.
+	fn replace(self, c: char, ascii: bool) -> Option<&'static str> {
+		if c.is_control() {
+			return Some("");
+		}
+
+		match self {
+			Self::Posix => (c == '/').then_some("-"),
+			Self::Portable | Self::Windows if !ascii => match c {
+				'"' => Some("'"),
+				'<' => Some("〈"),
+				'>' => Some("﹥"),
+				':' => Some(" "),
+				'/' | '\\' => Some("-"),
+				'?' => Some(""),
+				'*' => Some("﹡"),
+				'|' if self == Self::Windows => Some("｜"),
+				_ => None,
+			},
+			_ => match c {
+				'"' => Some("'"),
+				'<' => Some("("),
+				'>' => Some(")"),
+				':' => Some(" "),
+				'/' | '\\' | '|' => Some("-"),
+				'?' => Some(""),
+				'*' => Some("_"),
+				_ => None,
+			},
+		}
+	}
+}
+
+/// Turns cuesheet values into parts of file names.
+#[derive(Debug, Clone, Default)]
+pub struct Sanitizer {
+	pub profile: Profile,
+	/// Replaced in values before the rules of the profile, in order
+	pub replacements: Vec<(String, String)>,
+	/// Transliterate values to ASCII, e.g. `Мой` to `Moi`, and replace invalid characters with ASCII ones instead of lookalikes;
+	/// implied by [`Profile::Ascii`]
+	pub transliterate: bool,
+}
+
+impl Sanitizer {
+	pub fn new(profile: Profile) -> Self {
+		Self {
+			profile,
+			replacements: Vec::new(),
+			transliterate: false,
+		}
+	}
+
+	/// Transliterates values to ASCII.
+	pub fn transliterate(mut self, transliterate: bool) -> Self {
+		self.transliterate = transliterate;
+		self
+	}
+
+	/// Adds a replacement applied to values before the rules of the profile.
+	pub fn replace(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+		self.replacements.push((from.into(), to.into()));
+		self
+	}
+
+	/// Makes a value safe to substitute in a template; the result never contains a path separator.
+	pub fn value(&self, s: &str) -> String {
+		self.sanitize(s, self.transliterate || self.profile == Profile::Ascii)
+	}
+
+	/// Like [`Sanitizer::value`], but the result is always ASCII, as if transliterating were enabled.
+	pub fn ascii_value(&self, s: &str) -> String {
+		self.sanitize(s, true)
+	}
+
+	fn sanitize(&self, s: &str, ascii: bool) -> String {
+		let mut s = s.to_string();
+		for (from, to) in &self.replacements {
+			if !from.is_empty() {
+				s = s.replace(from.as_str(), to);
+			}
+		}
+		if ascii {
+			s = transliterate(&s);
+		}
+
+		s.chars()
+			.fold(String::with_capacity(s.len()), |mut buf, c| {
+				match self.profile.replace(c, ascii) {
+					Some(r) => buf.push_str(r),
+					None if ascii && !c.is_ascii() => buf.push('_'),
+					None => buf.push(c),
+				}
+
+				buf
+			})
+	}
+
+	/// Applies the rules for whole names to one component of an expanded path, such as avoiding reserved names.
+	///
+	/// `.` and `..` become `_`, so values can't point outside the output directory.
+	pub fn component(&self, s: &str) -> String {
+		if s == "." || s == ".." {
+			return "_".into();
+		} else if !self.profile.windows_names() {
+			return s.to_string();
+		}
+
+		// Windows drops trailing dots and spaces, so two names could end up the same
+		let s = s.trim_end_matches(['.', ' ']);
+		let stem = s.split('.').next().unwrap_or_default().trim_end();
+		if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+			format!("{stem}_{}", &s[stem.len()..])
+		} else if s.is_empty() {
+			"_".into()
+		} else {
+			s.to_string()
+		}
+	}
+}
+
+/// Transliterates Cyrillic, Greek, kana, accented Latin letters and other scripts to ASCII.
+///
+/// Characters without a transliteration become `_`.
+pub fn transliterate(s: &str) -> String {
+	deunicode::deunicode_with_tofu(s, "_").trim().to_string()
+}
+
+/// Shortens a name to at most `max` bytes without splitting characters that are displayed as one, then appends `suffix`.
+///
+/// If `keep_ext` is set, the extension is kept and `suffix` goes before it, so `"Long title.flac"` can become `"Long (2).flac"`.
+/// Trailing spaces and dots left by cutting are removed. Names that already fit are only changed by the suffix.
+pub fn truncate_name(name: &str, max: usize, keep_ext: bool, suffix: &str) -> String {
+	let (stem, ext) = match name.rfind('.') {
+		Some(i) if keep_ext && i > 0 => name.split_at(i),
+		_ => (name, ""),
+	};
+	if name.len() + suffix.len() <= max {
+		return format!("{stem}{suffix}{ext}");
+	}
+
+	// Very long extensions are cut like the rest of the name
+	let (stem, ext) = if ext.len() + suffix.len() < max {
+		(stem, ext)
+	} else {
+		(name, "")
+	};
+	let budget = max.saturating_sub(ext.len() + suffix.len());
+	let mut end = 0;
+	for (i, g) in stem.grapheme_indices(true) {
+		if i + g.len() > budget {
+			break;
+		}
+		end = i + g.len();
+	}
+
+	format!("{}{suffix}{ext}", stem[..end].trim_end_matches([' ', '.']))
+}
@@ -0,0 +1,540 @@
+use std::{
+	ops::ControlFlow,
+	path::{
+		Path,
+		PathBuf,
+	},
+	sync::Arc,
+	time::Duration,
+};
+
+use encoding_rs::Encoding;
+use rayon::prelude::*;
+
+use crate::{
+	accuraterip::{
+		self,
+		Checksums,
+	},
+	backend::{
+		Backend,
+		Ffmpeg,
+	},
+	cue::CueFile,
+	manifest,
+	plan::{
+		self,
+		Collision,
+		Encode,
+		Job,
+		Options,
+		Output,
+		Overwrite,
+	},
+	preset::Preset,
+	riplog,
+	sanitize::Sanitizer,
+	template::Template,
+	verify::{
+		self,
+		Report,
+	},
+	Error,
+	Result,
+	Warning,
+};
+
+/// Receives notifications while a [`Plan`] executes.
+///
+/// Jobs run in parallel, so methods may be called from several threads at once.
+/// `id` is the index of the job in [`Plan::jobs`].
+#[allow(unused_variables)]
+pub trait Observer: Sync {
+	fn job_started(&self, id: usize, job: &Job) {}
+	/// Called as the backend reports progress; return [`ControlFlow::Break`] to cancel the job.
+	fn job_progress(&self, id: usize, job: &Job, position: Duration) -> ControlFlow<()> {
+		ControlFlow::Continue(())
+	}
+	/// Called with the checksums of every track of a job before it runs.
+	///
+	/// They're computed from the range of the source each track is cut from, not from the split files.
+	/// Only called if computing checksums is enabled and the source is CD audio that can be decoded.
+	fn job_checksums(&self, id: usize, job: &Job, checksums: &[Checksums]) {}
+	/// Called after the tracks of a job have been verified, before [`Observer::job_finished`].
+	fn job_verified(&self, id: usize, job: &Job, report: &Report) {}
+	fn job_finished(&self, id: usize, job: &Job) {}
+	fn job_failed(&self, id: usize, job: &Job, error: &Error) {}
+	/// Called after every job has finished, for each manifest written.
+	fn manifest_written(&self, path: &Path) {}
+}
+
+impl Observer for () {}
+
+// What to do besides running the backend.
+#[derive(Debug, Copy, Clone, Default)]
+struct Extras {
+	verify: bool,
+	checksums: bool,
+	checksum_tags: bool,
+}
+
+/// Configures and plans splitting cuesheets.
+///
+/// ```no_run
+/// use hermes::{plan::Overwrite, preset::Preset, Splitter};
+///
+/// let plan = Splitter::new()
+///     .preset(Preset::Libopus)
+///     .out_dir("out")
+///     .overwrite(Overwrite::Never)
+///     .plan(["album.cue"])?;
+/// plan.execute(&())?;
+/// # Ok::<_, hermes::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Splitter {
+	opts: Options,
+	jobs: Option<usize>,
+	extras: Extras,
+	manifests: Vec<manifest::Kind>,
+	cue_encoding: Option<&'static Encoding>,
+	backend: Arc<dyn Backend>,
+}
+
+/// A list of jobs ready to be executed.
+#[derive(Debug)]
+pub struct Plan {
+	jobs: Vec<Job>,
+	warnings: Vec<Warning>,
+	threads: Option<usize>,
+	extras: Extras,
+	manifests: Vec<manifest::Kind>,
+	backend: Arc<dyn Backend>,
+}
+
+impl Default for Splitter {
+	fn default() -> Self {
+		Self {
+			opts: Options::default(),
+			jobs: None,
+			extras: Extras::default(),
+			manifests: Vec::new(),
+			cue_encoding: None,
+			backend: Arc::new(Ffmpeg::default()),
+		}
+	}
+}
+
+impl Splitter {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Sets the template determining output file names.
+	pub fn template(mut self, template: Template) -> Self {
+		self.opts.template = template;
+		self
+	}
+
+	/// Encodes tracks with a preset.
+	pub fn preset(mut self, preset: Preset) -> Self {
+		self.opts.output = Output::Preset(preset);
+		self
+	}
+
+	pub fn output(mut self, output: Output) -> Self {
+		self.opts.output = output;
+		self
+	}
+
+	/// Sets the output directory; defaults to `<cue_dir>/split`.
+	pub fn out_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.opts.out_dir = Some(dir.into());
+		self
+	}
+
+	pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+		self.opts.overwrite = overwrite;
+		self
+	}
+
+	/// Sets how values from cuesheets are made safe to use in file names.
+	pub fn sanitize(mut self, sanitizer: Sanitizer) -> Self {
+		self.opts.sanitize = sanitizer;
+		self
+	}
+
+	/// Shortens names of output files and directories to at most `max` bytes; `None` disables the limit, which defaults to 255.
+	pub fn max_name_bytes(mut self, max: Option<usize>) -> Self {
+		self.opts.max_name_bytes = max;
+		self
+	}
+
+	/// Shortens output paths to at most `max` bytes, including the output directory.
+	pub fn max_path_bytes(mut self, max: usize) -> Self {
+		self.opts.max_path_bytes = Some(max);
+		self
+	}
+
+	/// Sets what to do when several tracks would be written to the same path; defaults to failing.
+	pub fn on_collision(mut self, on_collision: Collision) -> Self {
+		self.opts.on_collision = on_collision;
+		self
+	}
+
+	/// Always re-encode, even if the source could be copied.
+	pub fn no_copy(mut self, no_copy: bool) -> Self {
+		self.opts.no_copy = no_copy;
+		self
+	}
+
+	/// Uses the ffmpeg backend with the ffmpeg executable at `path`.
+	pub fn ffmpeg(self, path: impl Into<PathBuf>) -> Self {
+		self.backend(Ffmpeg::new(path))
+	}
+
+	/// Sets the backend that runs jobs; defaults to [`Ffmpeg`].
+	pub fn backend(self, backend: impl Backend + 'static) -> Self {
+		self.backend_arc(Arc::new(backend))
+	}
+
+	pub fn backend_arc(mut self, backend: Arc<dyn Backend>) -> Self {
+		self.backend = backend;
+		self
+	}
+
+	/// Sets the maximum number of jobs run in parallel; defaults to the size of the global rayon thread pool.
+	pub fn jobs(mut self, n: usize) -> Self {
+		self.jobs = Some(n);
+		self
+	}
+
+	/// Decodes the tracks of every job after it runs and compares them with the source; see [`verify::verify`].
+	pub fn verify(mut self, verify: bool) -> Self {
+		self.extras.verify = verify;
+		self
+	}
+
+	/// Computes AccurateRip and CRC32 checksums of every track before splitting; see [`Observer::job_checksums`].
+	pub fn checksums(mut self, checksums: bool) -> Self {
+		self.extras.checksums = checksums;
+		self
+	}
+
+	/// Writes the checksums to the tags of every track; implies [`Splitter::checksums`].
+	///
+	/// Planning fails unless tracks are copied or encoded to WAV or FLAC, since lossy audio can't match the checksums.
+	/// The tracks are decoded after splitting, and a job fails if they differ from the source.
+	pub fn checksum_tags(mut self, tags: bool) -> Self {
+		self.extras.checksum_tags = tags;
+		self
+	}
+
+	/// Writes checksum manifests to every output directory once all jobs have run; see [`manifest::write`].
+	pub fn manifests(mut self, kinds: impl IntoIterator<Item = manifest::Kind>) -> Self {
+		self.manifests = kinds.into_iter().collect();
+		self
+	}
+
+	/// Decodes cuesheets with this encoding instead of detecting it.
+	pub fn cue_encoding(mut self, encoding: &'static Encoding) -> Self {
+		self.cue_encoding = Some(encoding);
+		self
+	}
+
+	/// Reads the cuesheets at `paths` and plans the jobs to split them.
+	pub fn plan<P: AsRef<Path>>(&self, paths: impl IntoIterator<Item = P>) -> Result<Plan> {
+		let cues = paths
+			.into_iter()
+			.map(|p| CueFile::read(p.as_ref(), self.cue_encoding))
+			.collect::<Result<Vec<_>>>()?;
+
+		self.plan_cues(cues)
+	}
+
+	/// Plans the jobs to split already parsed cuesheets.
+	///
+	/// Discs are split into chunks of tracks when there are fewer discs than threads, so every thread has work.
+	/// Fails if the backend can't run any of the jobs.
+	pub fn plan_cues(&self, cues: Vec<CueFile>) -> Result<Plan> {
+		let (jobs, mut warnings) = plan::plan(cues, &self.opts)?;
+		let threads = self.jobs.unwrap_or_else(rayon::current_num_threads);
+		let jobs = plan::chunk_jobs(jobs, threads);
+		for job in &jobs {
+			self.backend.check(job)?;
+			let mut job_warnings = self.backend.warnings(job);
+			if self.extras.checksums || self.extras.checksum_tags {
+				if !accuraterip::supports(job) {
+					job_warnings.push(Warning::NoChecksums(job.source.clone()));
+				} else if self.extras.checksum_tags && !is_lossless(&job.encode) {
+					return Err(Error::ChecksumTags {
+						path: job.cuts[0].path.clone(),
+						msg: "it's encoded lossily or with custom arguments, so its audio won't match them".into(),
+					});
+				}
+			}
+
+			// Chunks of the same disc have the same warnings
+			for w in job_warnings {
+				if !warnings.contains(&w) {
+					warnings.push(w);
+				}
+			}
+		}
+
+		Ok(Plan {
+			jobs,
+			warnings,
+			threads: self.jobs,
+			extras: self.extras,
+			manifests: self.manifests.clone(),
+			backend: Arc::clone(&self.backend),
+		})
+	}
+}
+
+impl Plan {
+	pub fn jobs(&self) -> &[Job] {
+		&self.jobs
+	}
+
+	/// Finds EAC or XLD logs next to every cuesheet and checks the audio of the tracks against the checksums they record.
+	///
+	/// Cuesheets without a log are left out. See [`riplog::check`].
+	pub fn check_logs(&self) -> Result<Vec<riplog::Report>> {
+		let mut cues = Vec::<(&Path, Vec<&Job>)>::new();
+		for job in &self.jobs {
+			match cues.iter_mut().find(|(p, _)| *p == job.cue) {
+				Some((_, jobs)) => jobs.push(job),
+				None => cues.push((&job.cue, vec![job])),
+			}
+		}
+
+		let check = || {
+			cues.into_par_iter()
+				.filter_map(|(cue, jobs)| {
+					let dir = cue.parent().unwrap_or(Path::new("."));
+					match riplog::find(dir) {
+						Ok(Some((path, log))) => Some(riplog::check(cue, &path, &log, &jobs)),
+						Ok(None) => None,
+						Err(e) => Some(Err(e)),
+					}
+				})
+				.collect()
+		};
+
+		match self.threads {
+			None => check(),
+			Some(n) => rayon::ThreadPoolBuilder::new()
+				.num_threads(n)
+				.build()
+				.map_err(Error::ThreadPool)?
+				.install(check),
+		}
+	}
+
+	/// Problems the backend reported while planning that don't prevent the jobs from running.
+	pub fn warnings(&self) -> &[Warning] {
+		&self.warnings
+	}
+
+	/// Runs every job, notifying `observer` as they progress.
+	///
+	/// Stops starting new jobs after the first failure and returns its error.
+	/// Manifests are only written if every job succeeded.
+	pub fn execute<O: Observer>(self, observer: &O) -> Result<()> {
+		let backend = &*self.backend;
+		let extras = self.extras;
+		let manifests = self.manifests;
+		// Jobs are consumed as they run, so the files they write are collected beforehand
+		let files = if manifests.is_empty() {
+			Vec::new()
+		} else {
+			self.jobs
+				.iter()
+				.flat_map(|j| j.new_files().map(Path::to_path_buf))
+				.collect()
+		};
+
+		let jobs = self.jobs;
+		let run = || {
+			jobs.into_par_iter()
+				.enumerate()
+				.try_for_each(|(id, job)| run_job(id, job, backend, extras, observer))?;
+
+			if !manifests.is_empty() {
+				for path in manifest::write(files.iter().map(PathBuf::as_path), &manifests)? {
+					observer.manifest_written(&path);
+				}
+			}
+			Ok(())
+		};
+
+		match self.threads {
+			None => run(),
+			Some(n) => rayon::ThreadPoolBuilder::new()
+				.num_threads(n)
+				.build()
+				.map_err(Error::ThreadPool)?
+				.install(run),
+		}
+	}
+}
+
+// Whether tracks decode to exactly the audio they're cut from, so checksums of the source are theirs too.
+fn is_lossless(encode: &Encode) -> bool {
+	matches!(
+		encode,
+		Encode::Copy | Encode::Preset(Preset::Wav | Preset::Flac | Preset::FlacComp10)
+	)
+}
+
+// Checksums are computed from the source first so they can be written to tags; returns whether they were.
+fn checksums<O: Observer>(id: usize, job: &mut Job, tags: bool, observer: &O) -> Result<bool> {
+	let Some(checksums) = accuraterip::compute(job)? else {
+		return Ok(false);
+	};
+
+	if tags {
+		for (cut, c) in job.cuts.iter_mut().zip(&checksums) {
+			cut.tags.extend(c.tags());
+		}
+	}
+	observer.job_checksums(id, job, &checksums);
+	Ok(tags)
+}
+
+fn run_job<O: Observer>(
+	id: usize,
+	mut job: Job,
+	backend: &dyn Backend,
+	extras: Extras,
+	observer: &O,
+) -> Result<()> {
+	observer.job_started(id, &job);
+	let mut res = Ok(false);
+	if extras.checksums || extras.checksum_tags {
+		res = checksums(id, &mut job, extras.checksum_tags, observer);
+	}
+
+	let job = &job;
+	let res = res.and_then(|tagged| {
+		job.create_dirs()?;
+		backend.run(job, &mut |pos| observer.job_progress(id, job, pos))?;
+		if !extras.verify && !tagged {
+			return Ok(());
+		}
+
+		let report = verify::verify(job)?;
+		if extras.verify {
+			observer.job_verified(id, job, &report);
+		}
+		// The tags only hold the checksums of the tracks if they decode to the source
+		if let Some(t) = report.mismatches().next().filter(|_| tagged) {
+			return Err(Error::ChecksumTags {
+				path: t.path.clone(),
+				msg: "its audio differs from the source they were computed from".into(),
+			});
+		}
+		Ok(())
+	});
+
+	match &res {
+		Ok(()) => observer.job_finished(id, job),
+		Err(e) => observer.job_failed(id, job, e),
+	}
+
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		env,
+		fs,
+		process,
+	};
+
+	use super::*;
+	use crate::{
+		audio::{
+			wav::pcm_fmt_chunk,
+			Format,
+		},
+		backend::{
+			Native,
+			Progress,
+		},
+	};
+
+	// Writes the whole source to every track, like a backend that fails to cut.
+	#[derive(Debug)]
+	struct Uncut;
+
+	impl Backend for Uncut {
+		fn name(&self) -> &str {
+			"uncut"
+		}
+
+		fn check(&self, _: &Job) -> Result<()> {
+			Ok(())
+		}
+
+		fn run(&self, job: &Job, _: &mut Progress) -> Result<()> {
+			for cut in &job.cuts {
+				fs::copy(&job.source, &cut.path).map_err(|e| Error::io(&cut.path, e))?;
+			}
+			Ok(())
+		}
+	}
+
+	// Two seconds of CD audio that differ from sample to sample.
+	fn wav() -> Vec<u8> {
+		let data = (0..44100 * 2 * 2)
+			.flat_map(|i: u32| (i as i16).wrapping_mul(7).to_le_bytes())
+			.collect::<Vec<_>>();
+		let fmt = pcm_fmt_chunk(Format::CD);
+		let mut buf = b"RIFF".to_vec();
+		buf.extend_from_slice(&(4 + 8 + fmt.len() as u32 + 8 + data.len() as u32).to_le_bytes());
+		buf.extend_from_slice(b"WAVEfmt ");
+		buf.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+		buf.extend_from_slice(&fmt);
+		buf.extend_from_slice(b"data");
+		buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+		buf.extend_from_slice(&data);
+		buf
+	}
+
+	#[test]
+	fn checksum_tags_need_tracks_that_match_the_source() {
+		let dir = env::temp_dir().join(format!("hermes-checksum-tags-{}", process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("a.wav"), wav()).unwrap();
+		let cue = dir.join("a.cue");
+		fs::write(
+			&cue,
+			"FILE \"a.wav\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 01 00:01:00\n",
+		)
+		.unwrap();
+
+		let split = |backend: Arc<dyn Backend>, preset| {
+			Splitter::new()
+				.template(Template::parse("<no>.<ext>").unwrap())
+				.preset(preset)
+				.out_dir(dir.join("out"))
+				.overwrite(Overwrite::Always)
+				.checksum_tags(true)
+				.backend_arc(backend)
+				.plan([&cue])
+				.and_then(|p| p.execute(&()))
+		};
+		let matching = split(Arc::new(Native::new()), Preset::Wav);
+		let uncut = split(Arc::new(Uncut), Preset::Wav);
+		let lossy = split(Arc::new(Uncut), Preset::Libmp3lame);
+		fs::remove_dir_all(&dir).unwrap();
+
+		matching.unwrap();
+		assert!(matches!(uncut, Err(Error::ChecksumTags { .. })), "{uncut:?}");
+		assert!(matches!(lossy, Err(Error::ChecksumTags { .. })), "{lossy:?}");
+	}
+}
@@ -1,88 +1,540 @@
-use std::ffi::OsString;
+use std::{
+	ffi::OsString,
+	mem,
+	ops::Range,
+};
 
-#[derive(Copy, Clone, Debug)]
-enum Token {
-	Lit(usize, usize),
-	Var(usize, usize),
+use crate::{
+	sanitize::{
+		self,
+		Sanitizer,
+	},
+	Error,
+	Result,
+};
+
+/// Variables that can be used in file name templates, besides `rem:KEY` for any `REM KEY` line of the cuesheet.
+pub const VARS: &[&str] = &[
+	"title",
+	"album",
+	"artist",
+	"no",
+	"year",
+	"ext",
+	"dir-name",
+	"disc",
+	"disc-total",
+	"track-total",
+	"genre",
+	"composer",
+	"catalog",
+	"isrc",
+	"album-artist",
+	"track-artist",
+	"date",
+	"cue-name",
+	"source-ext",
+];
+
+/// Returns whether `name` is a variable that can be used in templates.
+pub fn is_var(name: &str) -> bool {
+	VARS.contains(&name) || name.strip_prefix("rem:").is_some_and(|key| !key.is_empty())
+}
+
+/// Filters that can be applied to variables, as in `<title|lower>`.
+pub const FILTERS: &[&str] = &["lower", "upper", "ascii", "truncate", "pad", "replace", "default"];
+
+/// Transforms the value of a variable.
+#[derive(Clone, Debug)]
+enum Filter {
+	Lower,
+	Upper,
+	/// Transliterates to ASCII
+	Ascii,
+	/// Keeps at most this many characters, then trims trailing whitespace
+	Truncate(usize),
+	/// Pads with zeroes to the left to at least this many characters
+	Pad(usize),
+	Replace(String, String),
+	/// Used if the variable has no value; the only filter applied to missing values
+	Default(String),
+}
+
+impl Filter {
+	// Errors explain what's wrong with the arguments or list the valid filters; the caller points at the filter.
+	fn parse(s: &str) -> Result<Self, String> {
+		// The default text may contain colons
+		if let Some(text) = s.strip_prefix("default:") {
+			return Ok(Self::Default(text.to_string()));
+		}
+
+		let mut args = s.split(':');
+		let name = args.next().unwrap_or_default();
+		let args = args.collect::<Vec<_>>();
+		let err = |msg: &str| Err(msg.to_string());
+		let width = || match args.as_slice() {
+			[n] => n.parse::<usize>().ok(),
+			_ => None,
+		};
+
+		match name {
+			"lower" | "upper" | "ascii" if !args.is_empty() => err("it takes no arguments"),
+			"lower" => Ok(Self::Lower),
+			"upper" => Ok(Self::Upper),
+			"ascii" => Ok(Self::Ascii),
+			"truncate" | "pad" => match (name, width()) {
+				("truncate", Some(n)) => Ok(Self::Truncate(n)),
+				("pad", Some(n)) => Ok(Self::Pad(n)),
+				_ => err(&format!("expected a number of characters, e.g. |{name}:3")),
+			},
+			"default" => err("expected the text to use instead, e.g. |default:Unknown"),
+			"replace" => match args.as_slice() {
+				[from, to] if !from.is_empty() => Ok(Self::Replace(from.to_string(), to.to_string())),
+				_ => err("expected the text to replace and its replacement, e.g. |replace:&:and"),
+			},
+			_ => Err(format!("valid filters are {}", FILTERS.join(", "))),
+		}
+	}
+
+	fn apply(&self, s: Option<String>) -> Option<String> {
+		let s = match (self, s) {
+			(Self::Default(text), s) => {
+				return s.filter(|s| !s.is_empty()).or_else(|| Some(text.clone()));
+			}
+			(_, s) => s?,
+		};
+
+		Some(match self {
+			Self::Default(_) => unreachable!(),
+			Self::Lower => s.to_lowercase(),
+			Self::Upper => s.to_uppercase(),
+			Self::Ascii => sanitize::transliterate(&s),
+			Self::Truncate(n) => match s.char_indices().nth(*n) {
+				Some((i, _)) => s[..i].trim_end().to_string(),
+				None => s,
+			},
+			Self::Pad(n) => format!("{s:0>n$}"),
+			Self::Replace(from, to) => s.replace(from.as_str(), to),
+		})
+	}
 }
 
+/// Variables tried in order until one has a value, and the filters applied to it, in order.
+#[derive(Clone, Debug)]
+struct Var {
+	names: Vec<Range<usize>>,
+	filters: Vec<Filter>,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+	Lit(Range<usize>),
+	Var(Var),
+	/// `[...]`: left out if a variable inside has no value
+	Optional(Vec<Node>),
+}
+
+/// A file name template: literal text with `<var>` placeholders and optional `[...]` sections.
+///
+/// Optional sections can be nested; `[<year> - ]<album>` only writes the year and the dash if the year is known.
+/// Variables can be followed by filters: `<title|lower|truncate:40>`.
+/// `<album?dir-name|default:Unknown>` falls back to the next variable, then to the default text, if a variable has no value.
+/// A backslash makes the next `<`, `>`, `[`, `]` or `\\` literal; other backslashes are kept as they are.
 #[derive(Debug, Clone)]
 pub struct Template {
 	template: String,
-	tokens: Vec<Token>,
+	nodes: Vec<Node>,
+	warnings: Vec<String>,
 }
 
-impl Template {
-	pub fn new<S>(template: S, open: &str, close: &str) -> Self
-	where
-		S: Into<String> + AsRef<str>,
-	{
-		debug_assert!(!open.is_empty(), "the opening symbol can't be emty");
-		debug_assert!(!close.is_empty(), "the closing symbol can't be emty");
+// Formats a parse error pointing at byte `pos` of the template, followed by `hint` if it isn't empty.
+fn error_at(s: &str, pos: usize, msg: &str, hint: &str) -> Error {
+	let caret = " ".repeat(s[..pos].chars().count());
+	let mut msg = format!("{msg} at byte {pos}\n  {s}\n  {caret}^");
+	if !hint.is_empty() {
+		msg += "\n";
+		msg += hint;
+	}
+	Error::Template(msg)
+}
 
-		let s = template.as_ref();
-		let mut tokens = Vec::with_capacity(8);
+impl Template {
+	/// Parses a file name template, rejecting unknown variables and filters and unbalanced brackets.
+	///
+	/// Errors point at the position of the mistake.
+	pub fn parse(s: &str) -> Result<Self> {
+		// Positions of the open sections and the nodes that come before them
+		let mut open = Vec::<(usize, Vec<Node>)>::new();
+		let mut nodes = Vec::new();
+		let mut warnings = Vec::new();
+		let mut lit_start = 0;
 		let mut i = 0;
 
+		let flush = |nodes: &mut Vec<Node>, start: usize, end: usize| {
+			if start < end {
+				nodes.push(Node::Lit(start..end));
+			}
+		};
+
 		while i < s.len() {
-			let remaining = &s[i..];
-			match remaining.find(open) {
-				None => {
-					tokens.push(Token::Lit(i, s.len()));
-					break;
+			match s.as_bytes()[i] {
+				b'\\' if matches!(s.as_bytes().get(i + 1), Some(b'<' | b'>' | b'[' | b']' | b'\\')) => {
+					// The escaped character starts the next literal
+					flush(&mut nodes, lit_start, i);
+					lit_start = i + 1;
+					i += 2;
+					continue;
 				}
-				Some(0) => {
-					// let end = remaining.find('}').unwrap_or(remaining.len()) + i;
-					let end = match remaining[open.len()..]
-						.find(close)
-						.map(|n| n + open.len() + close.len() - 1)
-					{
-						Some(n) => n + i,
-						None => {
-							tokens.push(Token::Lit(i, s.len()));
-							break;
+				b'<' => {
+					let end = match s[i + 1..].find(['<', '>']) {
+						Some(n) if s.as_bytes()[i + 1 + n] == b'>' => i + 1 + n,
+						_ => {
+							return Err(error_at(
+								s,
+								i,
+								"unclosed '<'",
+								"help: write \\< for a literal '<'",
+							))
 						}
 					};
 
-					tokens.push(Token::Var(i + open.len(), end));
+					flush(&mut nodes, lit_start, i);
+					// Written as it is, `<>` would be invalid in file names on Windows
+					if end == i + 1 {
+						warnings.push(format!(
+							"the template contains an empty variable <> at byte {i}, which is left out"
+						));
+					} else {
+						nodes.push(Node::Var(parse_var(s, i + 1..end)?));
+					}
 					i = end + 1;
+					lit_start = i;
+					continue;
+				}
+				b'>' => {
+					return Err(error_at(
+						s,
+						i,
+						"unmatched '>'",
+						"help: write \\> for a literal '>'",
+					))
 				}
-				Some(n) => {
-					tokens.push(Token::Lit(i, i + n));
-					i += n;
+				b'[' => {
+					flush(&mut nodes, lit_start, i);
+					open.push((i, mem::take(&mut nodes)));
+					lit_start = i + 1;
 				}
+				b']' => {
+					flush(&mut nodes, lit_start, i);
+					let Some((start, outer)) = open.pop() else {
+						return Err(error_at(
+							s,
+							i,
+							"unmatched ']'",
+							"help: write \\] for a literal ']'",
+						));
+					};
+					let inner = mem::replace(&mut nodes, outer);
+					if !has_var(&inner) {
+						return Err(error_at(
+							s,
+							start,
+							"optional section without a variable",
+							"help: write \\[ for a literal '['",
+						));
+					}
+					nodes.push(Node::Optional(inner));
+					lit_start = i + 1;
+				}
+				_ => (),
 			}
+
+			i += 1;
 		}
 
-		Self {
-			template: template.into(),
-			tokens,
+		if let Some((start, _)) = open.last() {
+			return Err(error_at(
+				s,
+				*start,
+				"unclosed '['",
+				"help: write \\[ for a literal '['",
+			));
 		}
+		flush(&mut nodes, lit_start, s.len());
+
+		Ok(Self {
+			template: s.to_string(),
+			nodes,
+			warnings,
+		})
 	}
 
+	/// Mistakes in the template that don't prevent using it.
+	pub fn warnings(&self) -> &[String] {
+		&self.warnings
+	}
+
+	/// Returns every variable used, including inside optional sections.
 	pub fn vars(&self) -> impl Iterator<Item = &'_ str> {
-		self.tokens.iter().filter_map(|t| match t {
-			&Token::Var(start, end) => self.template.get(start..end),
-			Token::Lit(..) => None,
-		})
+		let mut vars = Vec::new();
+		collect_vars(&self.nodes, &mut vars);
+		vars.into_iter().map(|r| &self.template[r])
 	}
 
 	pub fn contains_var(&self, var: &str) -> bool {
 		self.vars().any(|s| s == var)
 	}
 
-	pub fn expand<'a, F>(&'a self, mut f: F) -> OsString
+	/// Expands the template, calling `f` for the value of every variable, or `None` if it has none.
+	///
+	/// Values, including the text of `default` filters, are made safe for file names with `sanitizer` after their filters,
+	/// so filters can't add path separators or characters the sanitizer removes. Values that aren't UTF-8 are kept as they are.
+	/// Optional sections are left out if a variable inside them has no value or an empty one.
+	/// Fails with the name of a variable outside optional sections that has no value.
+	pub fn expand<'a, F>(&'a self, sanitizer: &Sanitizer, mut f: F) -> Result<OsString, &'a str>
 	where
-		F: FnMut(&mut OsString, &'a str),
+		F: FnMut(&'a str) -> Option<OsString>,
 	{
 		let mut buf = OsString::with_capacity(self.template.len());
-		for &t in &self.tokens {
-			match t {
-				Token::Lit(start, end) => buf.push(&self.template[start..end]),
-				Token::Var(start, end) => f(&mut buf, &self.template[start..end]),
+		self.expand_nodes(&self.nodes, false, sanitizer, &mut f, &mut buf)?;
+		Ok(buf)
+	}
+
+	fn expand_nodes<'a, F>(
+		&'a self,
+		nodes: &[Node],
+		optional: bool,
+		sanitizer: &Sanitizer,
+		f: &mut F,
+		buf: &mut OsString,
+	) -> Result<(), &'a str>
+	where
+		F: FnMut(&'a str) -> Option<OsString>,
+	{
+		for node in nodes {
+			match node {
+				Node::Lit(r) => buf.push(&self.template[r.clone()]),
+				Node::Var(v) => {
+					let var = &self.template[v.names[0].clone()];
+					let mut val = None;
+					for name in &v.names {
+						match f(&self.template[name.clone()]) {
+							Some(s) if !s.is_empty() => {
+								val = Some(s);
+								break;
+							}
+							s => val = val.or(s),
+						}
+					}
+
+					if !v.filters.is_empty() {
+						let s = val.map(|s| s.to_string_lossy().into_owned());
+						val = v.filters.iter().fold(s, |s, f| f.apply(s)).map(Into::into);
+					}
+					// The ascii filter promises ASCII, which lookalike replacements would break
+					let ascii = v.filters.iter().any(|f| matches!(f, Filter::Ascii));
+					val = val.map(|s| match s.to_str() {
+						Some(s) if ascii => sanitizer.ascii_value(s).into(),
+						Some(s) => sanitizer.value(s).into(),
+						None => s,
+					});
+					match val {
+						Some(val) if !(optional && val.is_empty()) => buf.push(val),
+						_ => return Err(var),
+					}
+				}
+				Node::Optional(inner) => {
+					let mut section = OsString::new();
+					if self.expand_nodes(inner, true, sanitizer, f, &mut section).is_ok() {
+						buf.push(section);
+					}
+				}
 			}
 		}
 
-		buf
+		Ok(())
+	}
+}
+
+// Parses the inside of `<...>`: variable names separated by `?`, followed by filters separated by `|`.
+fn parse_var(s: &str, range: Range<usize>) -> Result<Var> {
+	let inner = &s[range.clone()];
+	let names_len = inner.find('|').unwrap_or(inner.len());
+	let mut names = Vec::new();
+	let mut start = range.start;
+	for name in inner[..names_len].split('?') {
+		if name.is_empty() {
+			return Err(error_at(s, start, "empty variable name", ""));
+		} else if !is_var(name) {
+			return Err(error_at(
+				s,
+				start,
+				&format!("unrecognized template variable <{name}>"),
+				"",
+			));
+		}
+		names.push(start..start + name.len());
+		start += name.len() + 1;
+	}
+
+	let mut filters = Vec::new();
+	let mut start = range.start + names_len + 1;
+	for f in inner[names_len..].split('|').skip(1) {
+		let filter = Filter::parse(f)
+			.map_err(|hint| {
+				error_at(
+					s,
+					start,
+					&format!("invalid template filter |{f}"),
+					&format!("help: {hint}"),
+				)
+			})?;
+		filters.push(filter);
+		start += f.len() + 1;
+	}
+
+	Ok(Var { names, filters })
+}
+
+fn has_var(nodes: &[Node]) -> bool {
+	nodes.iter().any(|n| match n {
+		Node::Lit(_) => false,
+		Node::Var(_) => true,
+		Node::Optional(inner) => has_var(inner),
+	})
+}
+
+fn collect_vars(nodes: &[Node], vars: &mut Vec<Range<usize>>) {
+	for n in nodes {
+		match n {
+			Node::Lit(_) => (),
+			Node::Var(v) => vars.extend(v.names.iter().cloned()),
+			Node::Optional(inner) => collect_vars(inner, vars),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn try_expand(sanitizer: &Sanitizer, template: &str, vars: &[(&str, &str)]) -> Result<String, String> {
+		Template::parse(template)
+			.unwrap()
+			.expand(sanitizer, |var| {
+				vars.iter().find(|(name, _)| *name == var).map(|(_, val)| val.into())
+			})
+			.map(|s| s.into_string().unwrap())
+			.map_err(str::to_string)
+	}
+
+	fn expand(template: &str, vars: &[(&str, &str)]) -> String {
+		try_expand(&Sanitizer::default(), template, vars).unwrap()
+	}
+
+	fn parse_error(template: &str) -> String {
+		match Template::parse(template) {
+			Err(Error::Template(msg)) => msg,
+			res => panic!("expected a template error, got {res:?}"),
+		}
+	}
+
+	#[test]
+	fn nested_optional_sections() {
+		let t = "[<year>[ disc <disc>] - ]<title>";
+		assert_eq!(expand(t, &[("title", "T")]), "T");
+		assert_eq!(expand(t, &[("title", "T"), ("disc", "2")]), "T");
+		assert_eq!(expand(t, &[("title", "T"), ("year", "2001")]), "2001 - T");
+		assert_eq!(
+			expand(t, &[("title", "T"), ("year", "2001"), ("disc", "2")]),
+			"2001 disc 2 - T"
+		);
+		// An empty value leaves out the section like a missing one
+		assert_eq!(expand(t, &[("title", "T"), ("year", "")]), "T");
+	}
+
+	#[test]
+	fn escapes() {
+		assert_eq!(expand(r"\[<year>\] \<no\> a\b \\", &[("year", "2001")]), r"[2001] <no> a\b \");
+		let t = Template::parse(r"\<title\>").unwrap();
+		assert_eq!(t.vars().count(), 0);
+		let t = Template::parse(r"[<year>\]]").unwrap();
+		assert_eq!(t.vars().collect::<Vec<_>>(), ["year"]);
+	}
+
+	#[test]
+	fn empty_variable_is_left_out_with_a_warning() {
+		let t = Template::parse("a<>b<title>").unwrap();
+		assert_eq!(
+			t.warnings(),
+			["the template contains an empty variable <> at byte 1, which is left out"]
+		);
+		assert_eq!(
+			t.expand(&Sanitizer::default(), |_| Some("T".into())).unwrap(),
+			"abT"
+		);
+		assert!(Template::parse("<title>").unwrap().warnings().is_empty());
+	}
+
+	#[test]
+	fn errors_point_at_the_mistake() {
+		assert_eq!(
+			parse_error("ab<title"),
+			"unclosed '<' at byte 2\n  ab<title\n    ^\nhelp: write \\< for a literal '<'"
+		);
+		assert_eq!(
+			parse_error("<title>]"),
+			"unmatched ']' at byte 7\n  <title>]\n         ^\nhelp: write \\] for a literal ']'"
+		);
+		// The caret counts characters, not bytes
+		assert_eq!(
+			parse_error("Čaj <title|nope>"),
+			"invalid template filter |nope at byte 12\n  Čaj <title|nope>\n             ^\nhelp: valid filters are lower, upper, ascii, truncate, pad, replace, default"
+		);
+		assert!(parse_error("<title|pad>").starts_with("invalid template filter |pad at byte 7\n"));
+		assert!(parse_error("<artist?nope>").starts_with("unrecognized template variable <nope> at byte 8\n"));
+	}
+
+	#[test]
+	fn fallbacks_are_tried_in_order() {
+		let t = "<album?dir-name|default:X>";
+		assert_eq!(expand(t, &[("album", "A"), ("dir-name", "D")]), "A");
+		assert_eq!(expand(t, &[("album", ""), ("dir-name", "D")]), "D");
+		assert_eq!(expand(t, &[("dir-name", "D")]), "D");
+		assert_eq!(expand(t, &[("album", "")]), "X");
+		assert_eq!(expand(t, &[]), "X");
+
+		let s = Sanitizer::default();
+		assert_eq!(try_expand(&s, "<album?dir-name>", &[("dir-name", "D")]), Ok("D".into()));
+		// An empty value is still a value outside optional sections
+		assert_eq!(try_expand(&s, "<album?dir-name>", &[("album", "")]), Ok("".into()));
+		// Missing values are reported by the first name
+		assert_eq!(try_expand(&s, "<album?dir-name>", &[]), Err("album".into()));
+	}
+
+	#[test]
+	fn default_text_may_contain_colons_and_question_marks() {
+		let t = Template::parse("<album|default:Vol: 1?|upper>").unwrap();
+		assert_eq!(t.vars().collect::<Vec<_>>(), ["album"]);
+		let posix = Sanitizer {
+			profile: sanitize::Profile::Posix,
+			..Sanitizer::default()
+		};
+		assert_eq!(try_expand(&posix, "<album|default:Vol: 1?|upper>", &[]), Ok("VOL: 1?".into()));
+		assert_eq!(expand("<album|default:Vol: 1?>", &[]), "Vol  1");
+	}
+
+	#[test]
+	fn filtered_values_are_sanitized() {
+		assert_eq!(expand("<title|ascii>", &[("title", "Čaj *x* <y>")]), "Caj _x_ (y)");
+		assert_eq!(expand("<title|replace:&:/>", &[("title", "R&B")]), "R-B");
+		assert_eq!(expand("<title|upper>", &[("title", "a/b")]), "A-B");
+	}
+
+	#[test]
+	fn default_text_is_sanitized() {
+		assert_eq!(expand("<album|default:AC/DC>", &[]), "AC-DC");
+		assert_eq!(expand("<album|default:What?>", &[]), "What");
+		assert_eq!(expand("<album|default:*|ascii>", &[]), "_");
 	}
 }
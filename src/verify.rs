@@ -0,0 +1,221 @@
+use std::{
+	ops::Range,
+	path::{
+		Path,
+		PathBuf,
+	},
+};
+
+use md5::{
+	Digest,
+	Md5,
+};
+
+use crate::{
+	audio::{
+		self,
+		Block,
+		Source,
+	},
+	plan::Job,
+	Result,
+};
+
+// Formats tracks can be decoded from; raw audio is left out since it says nothing about its layout.
+const DECODABLE_EXTS: &[&str] = &["wav", "flac", "aif", "aiff", "aifc"];
+
+/// How a split track compares to its range of the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+	/// The decoded track is identical to the source
+	Match,
+	/// The decoded track differs from the source
+	Mismatch {
+		/// Samples per channel in the source range
+		expected: u64,
+		/// Samples per channel in the track
+		actual: u64,
+	},
+	/// The track couldn't be compared; holds the reason
+	Skipped(String),
+}
+
+/// The outcome of verifying one track.
+#[derive(Debug, Clone)]
+pub struct Track {
+	pub path: PathBuf,
+	pub outcome: Outcome,
+}
+
+/// The outcome of verifying every track of a job.
+#[derive(Debug, Clone)]
+pub struct Report {
+	pub tracks: Vec<Track>,
+	/// MD5 of the decoded source from the start of the first track to the end of the last, if every track could be compared
+	pub source_md5: Option<[u8; 16]>,
+	/// MD5 of the decoded tracks one after another; equal to `source_md5` if nothing was lost
+	pub tracks_md5: Option<[u8; 16]>,
+}
+
+impl Report {
+	/// Whether every track was compared and is identical to the source.
+	pub fn is_ok(&self) -> bool {
+		self.source_md5.is_some()
+			&& self.source_md5 == self.tracks_md5
+			&& self.tracks.iter().all(|t| t.outcome == Outcome::Match)
+	}
+
+	pub fn mismatches(&self) -> impl Iterator<Item = &Track> {
+		self.tracks
+			.iter()
+			.filter(|t| matches!(t.outcome, Outcome::Mismatch { .. }))
+	}
+
+	pub fn skipped(&self) -> impl Iterator<Item = &Track> {
+		self.tracks
+			.iter()
+			.filter(|t| matches!(t.outcome, Outcome::Skipped(_)))
+	}
+}
+
+fn is_decodable(p: &Path) -> bool {
+	p.extension()
+		.and_then(|s| s.to_str())
+		.is_some_and(|ext| DECODABLE_EXTS.iter().any(|s| s.eq_ignore_ascii_case(ext)))
+}
+
+// MD5 of decoded samples and the number of samples per channel.
+type Hash = ([u8; 16], u64);
+
+// Hashes decoded samples the same way FLAC does: interleaved, little-endian and as wide as the sample size.
+struct Hasher {
+	md5: Md5,
+	samples: u64,
+}
+
+impl Hasher {
+	fn new() -> Self {
+		Self {
+			md5: Md5::new(),
+			samples: 0,
+		}
+	}
+
+	fn update(&mut self, block: &Block, range: Range<usize>, width: usize, buf: &mut Vec<u8>) {
+		buf.clear();
+		block.write_le(range.clone(), width, buf);
+		self.md5.update(&*buf);
+		self.samples += range.len() as u64;
+	}
+
+	fn finish(self) -> Hash {
+		(self.md5.finalize().into(), self.samples)
+	}
+}
+
+// Decodes the source once, hashing the range of every cut as well as all of them together.
+fn hash_source(job: &Job) -> Result<(Vec<Hash>, [u8; 16])> {
+	let mut src = Source::open(&job.source, &job.kind)?;
+	let format = src.format();
+	let width = usize::from(format.bytes_per_sample());
+	let ranges = audio::sample_ranges(&job.cuts, format, src.samples().unwrap_or(u64::MAX));
+	let end = ranges.iter().map(|r| r.end).max().unwrap_or(0);
+
+	let mut hashers = ranges.iter().map(|_| Hasher::new()).collect::<Vec<_>>();
+	let mut all = Hasher::new();
+	let mut block = Block::default();
+	let mut buf = Vec::new();
+
+	if let Some(first) = ranges.first() {
+		src.seek(first.start)?;
+	}
+	while src.read(&mut block)? {
+		let block_end = block.start + block.len as u64;
+		for (range, h) in ranges.iter().zip(&mut hashers) {
+			let start = range.start.max(block.start);
+			let end = range.end.min(block_end);
+			if start < end {
+				let r = (start - block.start) as usize..(end - block.start) as usize;
+				h.update(&block, r.clone(), width, &mut buf);
+				all.update(&block, r, width, &mut buf);
+			}
+		}
+
+		if block_end >= end {
+			break;
+		}
+	}
+
+	let tracks = hashers.into_iter().map(Hasher::finish).collect();
+	Ok((tracks, all.finish().0))
+}
+
+/// Decodes the tracks of a job that has run and compares them with the audio they were cut from.
+///
+/// Only WAV, AIFF and FLAC files can be decoded, so tracks in other formats (or cut from other formats) are skipped.
+pub fn verify(job: &Job) -> Result<Report> {
+	let skip_all = |reason: String| Report {
+		tracks: job
+			.cuts
+			.iter()
+			.map(|c| Track {
+				path: c.path.clone(),
+				outcome: Outcome::Skipped(reason.clone()),
+			})
+			.collect(),
+		source_md5: None,
+		tracks_md5: None,
+	};
+
+	if !audio::is_supported(&job.source, &job.kind) {
+		return Ok(skip_all(format!(
+			"the source {} can't be decoded",
+			job.source.display()
+		)));
+	}
+
+	let (expected, source_md5) = hash_source(job)?;
+	let mut all = Hasher::new();
+	let mut compared_all = true;
+	let mut block = Block::default();
+	let mut buf = Vec::new();
+
+	let mut tracks = Vec::with_capacity(job.cuts.len());
+	for (cut, (expected_md5, expected_len)) in job.cuts.iter().zip(expected) {
+		if !is_decodable(&cut.path) {
+			compared_all = false;
+			tracks.push(Track {
+				path: cut.path.clone(),
+				outcome: Outcome::Skipped("only WAV, AIFF and FLAC files can be decoded".into()),
+			});
+			continue;
+		}
+
+		let mut src = Source::open(&cut.path, "")?;
+		let width = usize::from(src.format().bytes_per_sample());
+		let mut h = Hasher::new();
+		while src.read(&mut block)? {
+			h.update(&block, 0..block.len, width, &mut buf);
+			all.update(&block, 0..block.len, width, &mut buf);
+		}
+
+		let (md5, len) = h.finish();
+		tracks.push(Track {
+			path: cut.path.clone(),
+			outcome: if md5 == expected_md5 && len == expected_len {
+				Outcome::Match
+			} else {
+				Outcome::Mismatch {
+					expected: expected_len,
+					actual: len,
+				}
+			},
+		});
+	}
+
+	Ok(Report {
+		tracks,
+		source_md5: compared_all.then_some(source_md5),
+		tracks_md5: compared_all.then(|| all.finish().0),
+	})
+}